@@ -0,0 +1,585 @@
+use clap::{
+    builder::{
+        PossibleValuesParser,
+        TypedValueParser,
+    },
+    Args,
+    Parser,
+    Subcommand,
+    ValueEnum,
+};
+use std::path::PathBuf;
+
+use kiln::types::{
+    id3::TagId,
+    options,
+};
+
+#[derive(Parser)]
+#[command(name = "kiln")]
+#[command(author = "Ben Buchanan")]
+#[command(version = "0.1.0")]
+#[command(about = "An id3 tag utility for the command line")]
+pub struct KilnArgs {
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// List tags for all selected files
+    List(ListArgs),
+
+    /// Set tags given an input file
+    Set(SetArgs),
+
+    /// Show how an input file differs from the tags on disk
+    Diff(DiffArgs),
+
+    /// Save the changes an input file would make as a plan, to apply later
+    Plan(PlanArgs),
+
+    /// Apply a plan, as long as none of its files have changed since
+    Apply(ApplyArgs),
+
+    /// Compare tags between two sets of files, without writing anything
+    Compare(CompareArgs),
+
+    /// Copy tags from one set of files to another
+    Copy(CopyArgs),
+
+    /// Derive tags from file names using a pattern
+    FromPath(FromPathArgs),
+
+    /// Rename and move files based on their tags
+    Rename(RenameArgs),
+
+    /// Search tag values for a pattern, printing the files that match
+    Search(SearchArgs),
+
+    /// Replace a pattern in tag values with something else
+    Replace(ReplaceArgs),
+
+    /// Make tags consistent, following the rules in a rules file
+    Normalize(NormalizeArgs),
+
+    /// Manage the index kiln keeps of tags it has already read
+    Index(IndexArgs),
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Turn off comments in the output
+    #[arg(short = 'c', long)]
+    pub no_comments: bool,
+
+    /// Force listing files with no tags
+    #[arg(short, long)]
+    pub force_empty: bool,
+
+    /// Print headers relative to this directory instead of the current one
+    #[arg(short, long)]
+    pub base: Option<PathBuf>,
+
+    /// Write the output to a file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Format to list tags in
+    #[arg(long, value_enum, default_value_t = Format::Kiln)]
+    pub format: Format,
+
+    /// How to list cover images in formats that can hold them
+    #[arg(long, value_enum, default_value_t = PictureFormat::Metadata)]
+    pub pictures: PictureFormat,
+
+    /// Order to list the frames under each header in
+    #[arg(long, value_enum, default_value_t = FrameOrder::Preferred)]
+    pub order: FrameOrder,
+
+    /// Only list files whose tags match this expression, like
+    /// 'TPE1 ~ "Maps" and TYER >= 2020 and missing(APIC)'
+    #[arg(long = "where", value_name = "EXPRESSION")]
+    pub filter: Option<String>,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+// Options shared by every subcommand that reads tags from an input file
+#[derive(Args)]
+pub struct InputArgs {
+    /// Input file to read tags from ('-' for stdin)
+    pub input_file: PathBuf,
+
+    /// Only warn about input that could not be parsed, instead of failing
+    #[arg(short, long)]
+    pub lenient: bool,
+
+    /// Specify a list of tags to preserve (will not be deleted)
+    #[arg(short, long = "preserve", value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub preserved_tags: Vec<TagId>,
+
+    /// Format of the input file (guessed from the file if not given)
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+
+    /// Treat empty cells in CSV/TSV input as deleting that tag
+    #[arg(long)]
+    pub delete_empty: bool,
+
+    /// Only change files whose tags match this expression, like
+    /// 'TPE1 ~ "Maps" and TYER >= 2020 and missing(APIC)'
+    #[arg(long = "where", value_name = "EXPRESSION")]
+    pub filter: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SetArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Order to list the frames under each header in
+    #[arg(long, value_enum, default_value_t = FrameOrder::Preferred)]
+    pub order: FrameOrder,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(Args)]
+pub struct DiffArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// When to color the output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Print the differences as JSON instead of a unified diff
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Write the plan to a file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ApplyArgs {
+    /// Plan file to apply ('-' for stdin)
+    pub plan_file: PathBuf,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    /// Glob string or input file for the original files
+    pub left: String,
+
+    /// Glob string or input file for the files to compare them against
+    pub right: String,
+
+    /// How to pair files from either side with each other
+    #[arg(long, value_enum, default_value_t = PairBy::Track)]
+    pub by: PairBy,
+
+    /// Specify a list of tags to leave out of the comparison
+    #[arg(short, long = "ignore", value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub ignored_tags: Vec<TagId>,
+
+    /// When to color the output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Print the differences as JSON instead of a unified diff
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct CopyArgs {
+    /// Glob string to select the files to copy tags from
+    pub source: String,
+
+    /// Glob string to select the files to copy tags to
+    pub dest: String,
+
+    /// How to pair files from either side with each other
+    #[arg(long, value_enum, default_value_t = PairBy::Order)]
+    pub by: PairBy,
+
+    /// Only copy these tags
+    #[arg(long, value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub include: Vec<TagId>,
+
+    /// Copy every tag but these
+    #[arg(long, value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub exclude: Vec<TagId>,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct FromPathArgs {
+    /// Pattern to match file names against, like '%{TPE1} - %{TALB} - %{TRCK} %{TIT2}'
+    pub pattern: String,
+
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Treat the pattern as a regex, with groups named after tags
+    #[arg(short, long)]
+    pub regex: bool,
+
+    /// Write the kiln file to a file instead of stdout
+    #[arg(short, long, conflicts_with = "apply")]
+    pub output: Option<PathBuf>,
+
+    /// Set the tags on the files instead of printing a kiln file
+    #[arg(long)]
+    pub apply: bool,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long, requires = "apply")]
+    pub ask: bool,
+
+    /// Order to list the frames under each header in
+    #[arg(long, value_enum, default_value_t = FrameOrder::Preferred)]
+    pub order: FrameOrder,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct RenameArgs {
+    /// Template for new paths, like '{TPE2}/{TYER} - {TALB}/{TRCK:02} {TIT2}.{ext}'
+    #[arg(required_unless_present = "undo")]
+    pub template: Option<String>,
+
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Directory that new paths are relative to
+    #[arg(short, long, default_value = ".")]
+    pub dest: PathBuf,
+
+    /// Only print the renames that would be made
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Ask for user confirmation before renaming files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Record the renames in this file, so they can be undone later
+    #[arg(long)]
+    pub undo_log: Option<PathBuf>,
+
+    /// Undo the renames recorded in an undo log
+    #[arg(long, conflicts_with_all = ["template", "undo_log"])]
+    pub undo: Option<PathBuf>,
+
+    /// How many files to read at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(Args)]
+pub struct SearchArgs {
+    /// Text to search for in tag values
+    pub pattern: String,
+
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Search every file beneath the directories the glob selects
+    #[arg(short = 'R', long)]
+    pub recursive: bool,
+
+    /// Treat the pattern as a regex
+    #[arg(short, long)]
+    pub regex: bool,
+
+    /// Match upper and lower case letters alike
+    #[arg(short, long)]
+    pub ignore_case: bool,
+
+    /// Only search these tags
+    #[arg(short, long = "field", ignore_case = true, value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub fields: Vec<TagId>,
+
+    /// When to color the output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// How many files to read at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(Args)]
+pub struct ReplaceArgs {
+    /// Text to look for in tag values
+    pub pattern: String,
+
+    /// Text to replace it with, which can use groups like '$1' with --regex
+    pub replacement: String,
+
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Change every file beneath the directories the glob selects
+    #[arg(short = 'R', long)]
+    pub recursive: bool,
+
+    /// Treat the pattern as a regex
+    #[arg(short, long)]
+    pub regex: bool,
+
+    /// Match upper and lower case letters alike
+    #[arg(short, long)]
+    pub ignore_case: bool,
+
+    /// Only replace in these tags
+    #[arg(short, long = "field", ignore_case = true, value_parser = tag_ids(), use_value_delimiter = true, value_delimiter = ',')]
+    pub fields: Vec<TagId>,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(Args)]
+pub struct NormalizeArgs {
+    /// TOML file of rules to follow
+    pub rules: PathBuf,
+
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// Change every file beneath the directories the glob selects
+    #[arg(short = 'R', long)]
+    pub recursive: bool,
+
+    /// Ask for user confirmation before writing tags to files
+    #[arg(short, long)]
+    pub ask: bool,
+
+    /// Version of id3 to write (multi-valued frames are joined with '/' for 2.3)
+    #[arg(long, value_enum, default_value_t = Id3Version::V24)]
+    pub id3_version: Id3Version,
+
+    /// How many files to read or write at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Read every file, instead of skipping files the index says are unchanged
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(Args)]
+pub struct IndexArgs {
+    #[command(subcommand)]
+    pub command: IndexCommand,
+}
+
+#[derive(Subcommand)]
+pub enum IndexCommand {
+    /// Read every selected file again and store its tags in the index
+    Rebuild(RebuildArgs),
+
+    /// Check that the index agrees with every selected file
+    Verify(VerifyArgs),
+}
+
+#[derive(Args)]
+pub struct RebuildArgs {
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+
+    /// How many files to read at once [default: one per CPU]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// Glob string to select files/directories
+    #[arg(default_value_t = String::from("./*"))]
+    pub glob: String,
+}
+
+// Frame ids are listed in lower case, like every other choice clap lists
+fn tag_ids() -> impl TypedValueParser<Value = TagId> {
+    PossibleValuesParser::new(TagId::ALL.map(|id| format!("{:?}", id).to_lowercase()))
+        .map(|id| id.parse::<TagId>().unwrap())
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum Format {
+    Kiln,
+    Json,
+    Csv,
+    Tsv,
+    Yaml,
+    Toml,
+}
+
+impl From<Format> for options::Format {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Kiln => Self::Kiln,
+            Format::Json => Self::Json,
+            Format::Csv => Self::Csv,
+            Format::Tsv => Self::Tsv,
+            Format::Yaml => Self::Yaml,
+            Format::Toml => Self::Toml,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum FrameOrder {
+    /// Alphabetically by frame id
+    Id,
+    /// Artist, album, track and title first, then the rest by id
+    Preferred,
+    /// The order the frames are in the file itself
+    File,
+}
+
+impl From<FrameOrder> for options::FrameOrder {
+    fn from(order: FrameOrder) -> Self {
+        match order {
+            FrameOrder::Id => Self::Id,
+            FrameOrder::Preferred => Self::Preferred,
+            FrameOrder::File => Self::File,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Id3Version {
+    #[value(name = "2.3")]
+    V23,
+    #[value(name = "2.4")]
+    V24,
+}
+
+impl From<Id3Version> for options::Id3Version {
+    fn from(version: Id3Version) -> Self {
+        match version {
+            Id3Version::V23 => Self::V23,
+            Id3Version::V24 => Self::V24,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum PictureFormat {
+    Metadata,
+    Base64,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum PairBy {
+    /// Position in the list of files, in natural order
+    Order,
+    /// Track number (TRCK)
+    Track,
+    /// File name, ignoring the directory and extension
+    Filename,
+    /// Byte-for-byte identical audio data, ignoring the tags. Only copies of
+    /// the same audio stream match, not re-encodes or other rips
+    AudioBytes,
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    io::{
+        stdin,
+        Read,
+    },
+};
+
+use kiln::{
+    files::TagCache,
+    plan::{
+        check_plan,
+        parse_plan,
+        planned_diff,
+    },
+    types::kiln::{
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+    },
+};
+
+use crate::{
+    args::ApplyArgs,
+    commands::set::{
+        commit_changes_to_files,
+        confirm_changes,
+        set_jobs,
+    },
+};
+
+pub fn apply_plan_file(args: ApplyArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+
+    let from_stdin = args.plan_file.as_os_str() == "-";
+    let (content, filename) = if from_stdin {
+        let mut content = String::new();
+        stdin().read_to_string(&mut content)?;
+        (content, String::from("<stdin>"))
+    } else {
+        match fs::read_to_string(&args.plan_file) {
+            Ok(content) => (content, args.plan_file.to_string_lossy().to_string()),
+            Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+        }
+    };
+
+    let plan = parse_plan(&filename, &content)?;
+    let diff = plan.files.iter()
+        .map(planned_diff)
+        .collect::<KilnResult<Vec<_>>>()?;
+
+    if confirm_changes(&diff, args.ask, from_stdin)? {
+        let mut cache = TagCache::new();
+        check_plan(&plan, &mut cache)?;
+        commit_changes_to_files(&plan, args.id3_version.into(), &mut cache)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::{
+        hash_map::DefaultHasher,
+        BTreeMap,
+        VecDeque,
+    },
+    fs,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io::{
+        self,
+        stdout,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        handle_glob_string,
+        TagCache,
+    },
+    order::natural_cmp,
+    plan::{
+        diff_tag_sets,
+        get_new_tags_from_sections,
+        get_old_tags_from_sections,
+    },
+    types::{
+        id3::{
+            TagId,
+            TagSet,
+        },
+        kiln::{
+            FileDiff,
+            KilnResult,
+        },
+    },
+};
+
+use crate::{
+    args::{
+        CompareArgs,
+        InputArgs,
+        PairBy,
+    },
+    commands::{
+        diff::{
+            diff_id,
+            ignore_broken_pipe,
+            json_file_diff,
+            output_unified,
+            set_color,
+        },
+        set::read_input_args,
+    },
+};
+
+pub type TagMap = BTreeMap<PathBuf, TagSet>;
+
+pub struct Pairing {
+    pub pairs: Vec<(PathBuf, PathBuf)>,
+    pub only_left: Vec<PathBuf>,
+    pub only_right: Vec<PathBuf>,
+}
+
+pub fn compare_tags(args: CompareArgs) -> KilnResult<()> {
+    set_color(args.color);
+
+    let mut cache = TagCache::new();
+    let mut left = read_side(&args.left, &mut cache)?;
+    let mut right = read_side(&args.right, &mut cache)?;
+    for tag_set in left.values_mut().chain(right.values_mut()) {
+        tag_set.retain(|e| !args.ignored_tags.contains(&e.id));
+    }
+
+    let Pairing { pairs, only_left, only_right } = pair_files(&left, &right, args.by)?;
+
+    let mut diff = Vec::new();
+    for (left_path, right_path) in pairs {
+        let mut filediff = diff_tag_sets(
+            right_path.to_string_lossy().to_string(),
+            &left[&left_path],
+            &right[&right_path],
+            &[]
+        );
+        if filediff.diffs.is_empty() { continue; }
+
+        filediff.diffs.sort_by_key(diff_id);
+        diff.push((left_path, filediff));
+    }
+
+    let unpaired = only_left.iter().map(|e| (&args.left, e))
+        .chain(only_right.iter().map(|e| (&args.right, e)))
+        .map(|(side, path)| format!("Only in {}: {}", side, path.display()))
+        .collect::<Vec<_>>();
+
+    let mut out = stdout().lock();
+    let res = if args.json {
+        // There's nowhere to put unpaired files in the JSON, so they go to stderr
+        unpaired.iter().for_each(|e| eprintln!("{}", e));
+        output_json(&diff, &mut out)
+    } else {
+        output_unified_all(&diff, &unpaired, &mut out)
+    };
+
+    ignore_broken_pipe(res)
+}
+
+// Either side can be a glob, which we read from disk, or an input file, which
+// we take to mean whatever its files would hold once it was set
+pub fn read_side(arg: &str, cache: &mut TagCache) -> KilnResult<TagMap> {
+    let path = Path::new(arg);
+    let is_input_file = arg == "-" || (path.is_file() && path.extension().is_none_or(|ext| ext != "mp3"));
+
+    if !is_input_file {
+        let filepaths = get_filepaths_from_glob(&handle_glob_string(arg))?;
+        cache.read_all(&filepaths)?;
+
+        let mut tag_map = TagMap::new();
+        for filepath in filepaths {
+            let tag_set = cache.tag_set(&filepath)?;
+            tag_map.insert(filepath, tag_set);
+        }
+        return Ok(tag_map);
+    }
+
+    let input = InputArgs {
+        input_file: PathBuf::from(arg),
+        lenient: false,
+        preserved_tags: Vec::new(),
+        format: None,
+        delete_empty: false,
+        filter: None,
+    };
+    let (sections, preserved_tags) = read_input_args(&input)?;
+    let old_tags = get_old_tags_from_sections(&sections, cache)?;
+    let new_tags = get_new_tags_from_sections(&sections)?;
+
+    // Any frames the input file preserves are left as they are on disk
+    let mut tag_map = TagMap::new();
+    for (header, mut tag_set) in new_tags {
+        let kept = old_tags[&header].iter()
+            .filter(|old| preserved_tags.contains(&old.id) && !tag_set.iter().any(|new| new.same_frame(old)))
+            .cloned()
+            .collect::<Vec<_>>();
+        tag_set.extend(kept);
+        tag_map.insert(PathBuf::from(header), tag_set);
+    }
+
+    Ok(tag_map)
+}
+
+// Files are paired up in natural order, so track 2 comes before track 10, and
+// if a key shows up more than once on either side, the first of each are
+// paired, then the second, and so on
+pub fn pair_files(left: &TagMap, right: &TagMap, by: PairBy) -> KilnResult<Pairing> {
+    let left = natural_order(left);
+    let right = natural_order(right);
+
+    if by == PairBy::Order {
+        let pairs = left.iter().map(|e| e.0.clone()).zip(right.iter().map(|e| e.0.clone())).collect::<Vec<_>>();
+        return Ok(Pairing {
+            only_left: left.iter().skip(pairs.len()).map(|e| e.0.clone()).collect(),
+            only_right: right.iter().skip(pairs.len()).map(|e| e.0.clone()).collect(),
+            pairs,
+        });
+    }
+
+    let mut by_key: BTreeMap<String, VecDeque<&PathBuf>> = BTreeMap::new();
+    let mut only_right = Vec::new();
+    for (path, tag_set) in right {
+        match pair_key(path, tag_set, by)? {
+            Some(key) => by_key.entry(key).or_default().push_back(path),
+            None => only_right.push(path.clone()),
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let mut only_left = Vec::new();
+    for (path, tag_set) in left {
+        let matched = match pair_key(path, tag_set, by)? {
+            Some(key) => by_key.get_mut(&key).and_then(|e| e.pop_front()),
+            None => None,
+        };
+        match matched {
+            Some(right_path) => pairs.push((path.clone(), right_path.clone())),
+            None => only_left.push(path.clone()),
+        }
+    }
+
+    only_right.extend(by_key.into_values().flatten().cloned());
+    only_right.sort_by(|a, b| natural_cmp(a, b));
+
+    Ok(Pairing { pairs, only_left, only_right })
+}
+
+fn natural_order(tag_map: &TagMap) -> Vec<(&PathBuf, &TagSet)> {
+    let mut files = tag_map.iter().collect::<Vec<_>>();
+    files.sort_by(|a, b| natural_cmp(a.0, b.0));
+    files
+}
+
+fn pair_key(path: &Path, tag_set: &TagSet, by: PairBy) -> KilnResult<Option<String>> {
+    match by {
+        PairBy::Order => Ok(None),
+        PairBy::Track => Ok(track_number(tag_set).map(|e| e.to_string())),
+        PairBy::Filename => Ok(path.file_stem().map(|e| e.to_string_lossy().to_string())),
+        PairBy::AudioBytes => audio_bytes_hash(path).map(Some),
+    }
+}
+
+// Track numbers may be given as "3" or "3/12", and we only care about the first
+fn track_number(tag_set: &TagSet) -> Option<u32> {
+    let track = tag_set.iter().find(|e| e.id == TagId::TRCK)?;
+    let values = track.values();
+    let number = values.first()?.split('/').next()?;
+    number.trim().parse().ok()
+}
+
+// Hash everything between the id3v2 tag at the start of the file and the
+// id3v1 tag at the end, so files with the same audio match whatever their tags.
+// This is no fingerprint: a single byte of difference gives another hash
+fn audio_bytes_hash(path: &Path) -> KilnResult<String> {
+    let data = fs::read(path)?;
+
+    let mut start = 0;
+    if data.len() >= 10 && &data[..3] == b"ID3" {
+        // The size is a 28 bit "synchsafe" integer, and doesn't count the
+        // header or the footer
+        let size = data[6..10].iter().fold(0, |acc, e| (acc << 7) | (*e as usize & 0x7f));
+        let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+        start = (10 + size + footer).min(data.len());
+    }
+
+    let mut end = data.len();
+    if end - start >= 128 && &data[end - 128..end - 125] == b"TAG" {
+        end -= 128;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    data[start..end].hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+fn output_unified_all(diff: &[(PathBuf, FileDiff)], unpaired: &[String], out: &mut dyn Write) -> io::Result<()> {
+    for (left_path, filediff) in diff {
+        output_unified(left_path, filediff, out)?;
+    }
+    for line in unpaired {
+        writeln!(out, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+fn output_json(diff: &[(PathBuf, FileDiff)], out: &mut dyn Write) -> io::Result<()> {
+    let json = diff.iter()
+        .map(|(left_path, filediff)| {
+            let mut json = json_file_diff(filediff);
+            json.original = Some(left_path.display().to_string());
+            json
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(&mut *out, &json)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::{
+        Tag,
+        TagLike,
+        Version,
+    };
+
+    use super::*;
+
+    fn tag_map(paths: &[&str]) -> TagMap {
+        paths.iter().map(|e| (PathBuf::from(e), TagSet::new())).collect()
+    }
+
+    #[test]
+    fn files_pair_up_in_natural_order() {
+        let left = tag_map(&["a/1.mp3", "a/2.mp3", "a/10.mp3", "a/11.mp3"]);
+        let right = tag_map(&["b/01.mp3", "b/02.mp3", "b/10.mp3"]);
+
+        let pairing = pair_files(&left, &right, PairBy::Order).unwrap();
+        let pairs = pairing.pairs.iter()
+            .map(|(a, b)| (a.to_str().unwrap(), b.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, [("a/1.mp3", "b/01.mp3"), ("a/2.mp3", "b/02.mp3"), ("a/10.mp3", "b/10.mp3")]);
+        assert_eq!(pairing.only_left, [PathBuf::from("a/11.mp3")]);
+        assert!(pairing.only_right.is_empty());
+    }
+
+    // Tags of any size are skipped, but a single changed byte of audio is
+    // enough for files not to pair up
+    #[test]
+    fn only_identical_audio_pairs_by_audio_bytes() {
+        let dir = std::env::temp_dir().join(format!("kiln-compare-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let audio = vec![0xff; 4096];
+        let mut other_audio = audio.clone();
+        other_audio[100] = 0;
+        for (name, title, audio) in [("a.mp3", "Short", &audio), ("b.mp3", "A much longer title", &audio), ("c.mp3", "Short", &other_audio)] {
+            fs::write(dir.join(name), audio).unwrap();
+            let mut tag = Tag::new();
+            tag.set_title(title);
+            tag.write_to_path(dir.join(name), Version::Id3v24).unwrap();
+        }
+
+        let left = tag_map(&[dir.join("a.mp3").to_str().unwrap()]);
+        let right = tag_map(&[dir.join("c.mp3").to_str().unwrap(), dir.join("b.mp3").to_str().unwrap()]);
+        let pairing = pair_files(&left, &right, PairBy::AudioBytes).unwrap();
+        assert_eq!(pairing.pairs, [(dir.join("a.mp3"), dir.join("b.mp3"))]);
+        assert_eq!(pairing.only_right, [dir.join("c.mp3")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use glob::Pattern;
+
+use kiln::{
+    files::TagCache,
+    order::sort_diff,
+    plan::calculate_diff,
+    types::{
+        id3::TagId,
+        kiln::{
+            KilnResult,
+            Section,
+        },
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::CopyArgs,
+    commands::{
+        compare::{
+            pair_files,
+            read_side,
+            Pairing,
+        },
+        set::{
+            apply_diff,
+            set_jobs,
+        },
+    },
+};
+
+pub fn copy_tags(args: CopyArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+
+    // Only the destination files are planned and written, so their cache is
+    // kept for working out changes
+    let source = read_side(&args.source, &mut TagCache::new())?;
+    let mut cache = TagCache::new();
+    let dest = read_side(&args.dest, &mut cache)?;
+
+    let copied = |id: &TagId| {
+        (args.include.is_empty() || args.include.contains(id)) && !args.exclude.contains(id)
+    };
+
+    let Pairing { pairs, only_left, only_right } = pair_files(&source, &dest, args.by)?;
+    for path in only_left.iter().chain(&only_right) {
+        eprintln!("Nothing to pair {} with, skipping it", path.display());
+    }
+
+    // Each destination gets a section holding the source's copied tags, so
+    // that copied frames missing from the source are deleted, while frames we
+    // aren't copying are preserved as they are
+    let sections = pairs.into_iter()
+        .map(|(source_path, dest_path)| Section {
+            header: Pattern::escape(&dest_path.to_string_lossy()),
+            tag_set: source[&source_path].iter()
+                .filter(|e| copied(&e.id))
+                .cloned()
+                .collect(),
+        })
+        .collect();
+    let preserved_tags = TagId::ALL.iter()
+        .filter(|id| !copied(id))
+        .copied()
+        .collect();
+
+    let mut diff = calculate_diff(sections, preserved_tags, &mut cache)?;
+    sort_diff(&mut diff, FrameOrder::default(), &mut cache)?;
+    apply_diff(diff, args.ask, false, args.id3_version.into(), &mut cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::{
+        Tag,
+        TagLike,
+        Version,
+    };
+    use std::fs;
+
+    use super::*;
+    use crate::args::{
+        Id3Version,
+        PairBy,
+    };
+
+    // Copying is done by order unless told otherwise, so track 10 has to come
+    // after track 2 on both sides, however the numbers are padded
+    #[test]
+    fn tracks_are_copied_in_natural_order() {
+        let dir = std::env::temp_dir().join(format!("kiln-copy-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+
+        for (source, dest) in [("1", "01"), ("2", "02"), ("10", "10")] {
+            let mut tag = Tag::new();
+            tag.set_title(format!("Track {}", source));
+            fs::write(dir.join("a").join(format!("{}.mp3", source)), []).unwrap();
+            tag.write_to_path(dir.join("a").join(format!("{}.mp3", source)), Version::Id3v24).unwrap();
+            fs::write(dir.join("b").join(format!("{}.mp3", dest)), []).unwrap();
+        }
+
+        let glob = |side: &str| Pattern::escape(&dir.join(side).to_string_lossy()) + "/*";
+        let args = CopyArgs {
+            source: glob("a"),
+            dest: glob("b"),
+            by: PairBy::Order,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ask: false,
+            id3_version: Id3Version::V24,
+            jobs: None,
+        };
+        copy_tags(args).unwrap();
+
+        for (source, dest) in [("1", "01"), ("2", "02"), ("10", "10")] {
+            let tag = Tag::read_from_path(dir.join("b").join(format!("{}.mp3", dest))).unwrap();
+            assert_eq!(tag.title(), Some(format!("Track {}", source).as_str()));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
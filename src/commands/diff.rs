@@ -0,0 +1,190 @@
+use colored::Colorize;
+use std::{
+    io::{
+        self,
+        stdout,
+        IsTerminal,
+        Write,
+    },
+    path::{
+        Path,
+        MAIN_SEPARATOR,
+    },
+};
+
+use kiln::{
+    files::TagCache,
+    json::tag_to_json,
+    order::natural_cmp,
+    parse::format_tag,
+    plan::calculate_diff,
+    query::{
+        filter_diff,
+        parse_query,
+    },
+    types::{
+        id3::TagId,
+        json::{
+            JsonChange,
+            JsonFileDiff,
+        },
+        kiln::{
+            Diff,
+            FileDiff,
+            KilnResult,
+        },
+    },
+};
+
+use crate::{
+    args::{
+        ColorChoice,
+        DiffArgs,
+    },
+    commands::set::read_input_args,
+};
+
+pub fn diff_tags(args: DiffArgs) -> KilnResult<()> {
+    set_color(args.color);
+
+    let query = args.input.filter.as_deref().map(parse_query).transpose()?;
+    let (sections, preserved_tags) = read_input_args(&args.input)?;
+    let mut cache = TagCache::new();
+    let mut diff = calculate_diff(sections, preserved_tags, &mut cache)?;
+    if let Some(query) = &query {
+        diff = filter_diff(diff, query, &mut cache)?;
+    }
+
+    // Keep the output stable between runs, so it can be compared and reviewed
+    diff.retain(|e| !e.diffs.is_empty());
+    diff.sort_by(|a, b| natural_cmp(&a.filepath, &b.filepath));
+    for filediff in &mut diff {
+        filediff.diffs.sort_by_key(diff_id);
+    }
+
+    let mut out = stdout().lock();
+    let res = if args.json {
+        output_json(&diff, &mut out)
+    } else {
+        diff.iter().try_for_each(|filediff| output_unified(&filediff.filepath, filediff, &mut out))
+    };
+
+    ignore_broken_pipe(res)
+}
+
+pub fn set_color(color: ColorChoice) {
+    let use_color = match color {
+        ColorChoice::Auto => stdout().is_terminal(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    colored::control::set_override(use_color);
+}
+
+// Being cut off by something like 'head' isn't an error worth reporting
+pub fn ignore_broken_pipe(res: io::Result<()>) -> KilnResult<()> {
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+pub fn diff_id(diff: &Diff) -> TagId {
+    match diff {
+        Diff::Add(tag) | Diff::Delete(tag) | Diff::Modify(tag, _) => tag.id,
+    }
+}
+
+// Each file gets a single hunk holding only the changed lines, written as they
+// would appear in a kiln file
+pub fn output_unified(old_path: &Path, filediff: &FileDiff, out: &mut dyn Write) -> io::Result<()> {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let mut lines = Vec::new();
+
+    for diff in &filediff.diffs {
+        let (old, new) = match diff {
+            Diff::Add(tag) => (Vec::new(), format_tag(tag)),
+            Diff::Delete(tag) => (format_tag(tag), Vec::new()),
+            Diff::Modify(old, new) => (format_tag(old), format_tag(new)),
+        };
+
+        lines.extend(old.iter().map(|e| format!("-{}", e).red()));
+        lines.extend(new.iter().map(|e| format!("+{}", e).green()));
+        removed.extend(old);
+        added.extend(new);
+    }
+
+    writeln!(out, "{}", format!("--- {}", prefixed_path("a", old_path)).bold())?;
+    writeln!(out, "{}", format!("+++ {}", prefixed_path("b", &filediff.filepath)).bold())?;
+    writeln!(out, "{}", format!("@@ -{} +{} @@", hunk_range(removed.len()), hunk_range(added.len())).cyan())?;
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+// Like git, an absolute path loses its leading separator after the prefix, so
+// '/music/a.mp3' gives 'a/music/a.mp3' rather than 'a//music/a.mp3'
+fn prefixed_path(prefix: &str, path: &Path) -> String {
+    let path = path.display().to_string();
+    format!("{}/{}", prefix, path.trim_start_matches(['/', MAIN_SEPARATOR]))
+}
+
+fn hunk_range(count: usize) -> String {
+    match count {
+        0 => String::from("0,0"),
+        count => format!("1,{}", count),
+    }
+}
+
+fn output_json(diff: &[FileDiff], out: &mut dyn Write) -> io::Result<()> {
+    let json = diff.iter().map(json_file_diff).collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(&mut *out, &json)?;
+    writeln!(out)
+}
+
+pub fn json_file_diff(filediff: &FileDiff) -> JsonFileDiff {
+    JsonFileDiff {
+        path: filediff.filepath.display().to_string(),
+        original: None,
+        changes: filediff.diffs.iter()
+            .map(|diff| match diff {
+                Diff::Add(tag) => JsonChange::Add { id: tag.id, new: tag_to_json(tag, false) },
+                Diff::Delete(tag) => JsonChange::Delete { id: tag.id, old: tag_to_json(tag, false) },
+                Diff::Modify(old, new) => JsonChange::Modify {
+                    id: old.id,
+                    old: tag_to_json(old, false),
+                    new: tag_to_json(new, false),
+                },
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use kiln::types::id3::TagPair;
+
+    use super::*;
+
+    fn headers(old_path: &str, new_path: &str) -> Vec<String> {
+        colored::control::set_override(false);
+        let tag = TagPair::from_str("TIT2", "Title").unwrap();
+        let filediff = FileDiff { filepath: PathBuf::from(new_path), diffs: vec![Diff::Add(tag)] };
+
+        let mut out = Vec::new();
+        output_unified(Path::new(old_path), &filediff, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().take(2).map(String::from).collect()
+    }
+
+    #[test]
+    fn absolute_paths_get_a_single_separator() {
+        assert_eq!(headers("/music/a.mp3", "/music/b.mp3"), ["--- a/music/a.mp3", "+++ b/music/b.mp3"]);
+        assert_eq!(headers("a.mp3", "Album/b.mp3"), ["--- a/a.mp3", "+++ b/Album/b.mp3"]);
+    }
+}
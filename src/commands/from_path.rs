@@ -0,0 +1,301 @@
+use glob::Pattern;
+use regex::Regex;
+use std::{
+    fs,
+    io::{
+        stdout,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        handle_glob_string,
+        parent_dir,
+        write_atomically,
+        TagCache,
+    },
+    order::{
+        natural_cmp,
+        sort_diff,
+        sort_tags,
+    },
+    plan::calculate_diff,
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+            TagSet,
+        },
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            Section,
+        },
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::FromPathArgs,
+    commands::{
+        list::{
+            file_header,
+            output_tag,
+            paths_directive,
+        },
+        set::{
+            apply_diff,
+            set_jobs,
+        },
+    },
+};
+
+pub fn tags_from_path(args: FromPathArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+
+    let regex = match args.regex {
+        true => build_regex(&args.pattern)?,
+        false => pattern_to_regex(&args.pattern)?,
+    };
+    let groups = tag_groups(&regex)?;
+
+    // A pattern with slashes in it matches that many directories above the
+    // file as well as its name
+    let depth = args.pattern.matches('/').count();
+
+    // Files come in natural order, so track 2 is listed before track 10
+    let mut filepaths = get_filepaths_from_glob(&handle_glob_string(&args.glob))?;
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+
+    let mut files = Vec::new();
+    for filepath in filepaths {
+        let name = match_name(&filepath, depth);
+        let captures = match regex.captures(&name) {
+            Some(captures) => captures,
+            None => {
+                eprintln!("{} doesn't match the pattern, skipping it", filepath.display());
+                continue;
+            },
+        };
+
+        let mut tag_set = TagSet::new();
+        for (group, id) in &groups {
+            let value = match captures.name(group) {
+                Some(value) if !value.as_str().is_empty() => value.as_str(),
+                _ => continue,
+            };
+            match TagPair::from_str(&format!("{:?}", id), value) {
+                Ok(tag_pair) => tag_set.insert(tag_pair),
+                Err(e) => return Err(KilnError::new(e.kind, format!("{}: {}", filepath.display(), e.message))),
+            };
+        }
+
+        files.push((filepath, tag_set));
+    }
+
+    if args.apply {
+        let sections = files.into_iter()
+            .map(|(filepath, tag_set)| Section { header: Pattern::escape(&filepath.to_string_lossy()), tag_set })
+            .collect();
+
+        // Only the tags we found are touched, and everything else is left alone
+        let preserved_tags = TagId::ALL.to_vec();
+        let mut cache = TagCache::new();
+        let mut diff = calculate_diff(sections, preserved_tags, &mut cache)?;
+        sort_diff(&mut diff, args.order.into(), &mut cache)?;
+        return apply_diff(diff, args.ask, false, args.id3_version.into(), &mut cache);
+    }
+
+    let mut cache = TagCache::new();
+    match &args.output {
+        Some(path) => {
+            let base = fs::canonicalize(parent_dir(path))?;
+            let mut buf = Vec::new();
+            output_tags(&files, Some(&base), args.order.into(), &mut cache, &mut buf)?;
+            write_atomically(path, &buf)
+        },
+        None => output_tags(&files, None, args.order.into(), &mut cache, &mut stdout().lock()),
+    }
+}
+
+// Turn a pattern like '%{TPE1} - %{TIT2}' into a regex that has to match the
+// whole name, where each tag takes as little of it as it can
+fn pattern_to_regex(pattern: &str) -> KilnResult<Regex> {
+    let mut regex = String::from("^");
+    let mut rest = pattern;
+
+    while let Some(start) = rest.find("%{") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(KilnError::new(KilnErrorKind::Parse, "unterminated '%{' in pattern".to_string())),
+        };
+        regex.push_str(&regex::escape(&rest[..start]));
+        regex.push_str(&format!("(?P<{}>.+?)", &rest[start + 2..end]));
+        rest = &rest[end + 1..];
+    }
+
+    regex.push_str(&regex::escape(rest));
+    regex.push('$');
+
+    build_regex(&regex)
+}
+
+fn build_regex(regex: &str) -> KilnResult<Regex> {
+    match Regex::new(regex) {
+        Ok(regex) => Ok(regex),
+        Err(e) => Err(KilnError::new(KilnErrorKind::Parse, e.to_string())),
+    }
+}
+
+// Every named group has to be a tag, but unnamed groups are fine to use for
+// parts of the name we don't care about
+fn tag_groups(regex: &Regex) -> KilnResult<Vec<(String, TagId)>> {
+    let mut groups = Vec::new();
+
+    for name in regex.capture_names().flatten() {
+        match name.parse::<TagId>() {
+            Ok(id) => groups.push((name.to_string(), id)),
+            Err(_) => return Err(KilnError::new(
+                KilnErrorKind::ID3,
+                format!("{} is not a valid id3 tag for kiln", name)
+            )),
+        }
+    }
+
+    Ok(groups)
+}
+
+// The file name without its extension, along with as many of the directories
+// above it as the pattern asks for
+fn match_name(filepath: &Path, depth: usize) -> String {
+    let mut parts = vec![filepath.file_stem().unwrap_or_default().to_string_lossy().to_string()];
+    if let Some(parent) = filepath.parent() {
+        parts.extend(
+            parent.components()
+                .rev()
+                .take(depth)
+                .map(|e| e.as_os_str().to_string_lossy().to_string())
+        );
+    }
+    parts.reverse();
+
+    parts.join("/")
+}
+
+fn output_tags(
+    files: &[(PathBuf, TagSet)],
+    base: Option<&Path>,
+    order: FrameOrder,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    paths_directive(base, out)?;
+
+    for (filepath, tag_set) in files {
+        if tag_set.is_empty() { continue; }
+
+        // Escape the path so that set doesn't treat it as a glob
+        writeln!(out, "[{}]", Pattern::escape(&file_header(filepath, base)?))?;
+
+        let file_order = match order {
+            FrameOrder::File => cache.frame_order(filepath)?,
+            _ => Vec::new(),
+        };
+        for tag in sort_tags(tag_set, order, &file_order) {
+            output_tag(tag, out)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::{
+        Commands,
+        KilnArgs,
+    };
+
+    fn captured(pattern: &str, name: &str) -> Option<Vec<(TagId, String)>> {
+        let regex = pattern_to_regex(pattern).unwrap();
+        let captures = regex.captures(name)?;
+        let tags = tag_groups(&regex).unwrap().into_iter()
+            .filter_map(|(group, id)| captures.name(&group).map(|e| (id, e.as_str().to_string())))
+            .collect();
+        Some(tags)
+    }
+
+    #[test]
+    fn patterns_match_the_whole_name_taking_as_little_as_they_can() {
+        assert_eq!(
+            captured("%{TPE1} - %{TIT2}", "Maps - Opal - Drifters").unwrap(),
+            [(TagId::TPE1, "Maps".to_string()), (TagId::TIT2, "Opal - Drifters".to_string())]
+        );
+
+        // Everything outside a placeholder is matched as it is
+        assert_eq!(
+            captured("(%{TRCK}) %{TIT2}?", "(01) Who?").unwrap(),
+            [(TagId::TRCK, "01".to_string()), (TagId::TIT2, "Who".to_string())]
+        );
+        assert_eq!(captured("(%{TRCK}) %{TIT2}?", "(01) Who"), None);
+        assert_eq!(captured("%{TRCK}.%{TIT2}", "01 Who"), None);
+        assert_eq!(captured("%{TRCK} %{TIT2}", "Intro"), None);
+    }
+
+    #[test]
+    fn bad_patterns_are_refused() {
+        assert!(pattern_to_regex("%{TPE1} - %{TIT2").unwrap_err().message.contains("unterminated"));
+        let e = tag_groups(&pattern_to_regex("%{TPE1} - %{ARTIST}").unwrap()).unwrap_err();
+        assert!(e.message.contains("ARTIST is not a valid id3 tag"));
+        assert!(tag_groups(&build_regex("(\\d+) (?P<TIT2>.+)").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn names_include_as_many_directories_as_the_pattern_has_slashes() {
+        let path = Path::new("Music/Maps/Opal Drifters/01 Opal.mp3");
+        assert_eq!(match_name(path, 0), "01 Opal");
+        assert_eq!(match_name(path, 1), "Opal Drifters/01 Opal");
+        assert_eq!(match_name(path, 2), "Maps/Opal Drifters/01 Opal");
+        assert_eq!(match_name(Path::new("01 Opal.mp3"), 2), "01 Opal");
+    }
+
+    // Files are listed in natural order, and frames in the order asked for
+    #[test]
+    fn output_follows_natural_and_frame_order() {
+        let dir = std::env::temp_dir().join(format!("kiln-from-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["10 Maps - Ten.mp3", "2 Maps - Two.mp3"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let output = |order: &str| {
+            let glob = Pattern::escape(&dir.to_string_lossy()) + "/*.mp3";
+            let out = dir.join("out.kiln");
+            let args = ["kiln", "from-path", "%{TRCK} %{TPE1} - %{TIT2}", &glob, "-o", out.to_str().unwrap(), "--order", order];
+            match KilnArgs::parse_from(args).command {
+                Commands::FromPath(args) => tags_from_path(args).unwrap(),
+                _ => unreachable!(),
+            }
+            fs::read_to_string(&out).unwrap()
+        };
+
+        assert_eq!(
+            output("preferred"),
+            "[2 Maps - Two.mp3]\nTPE1 = Maps\nTRCK = 2\nTIT2 = Two\n\n[10 Maps - Ten.mp3]\nTPE1 = Maps\nTRCK = 10\nTIT2 = Ten\n\n"
+        );
+        assert!(output("id").starts_with("[2 Maps - Two.mp3]\nTIT2 = Two\nTPE1 = Maps\nTRCK = 2\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::PathBuf,
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        handle_glob_string,
+        parent_dir,
+        TagCache,
+    },
+    index::{
+        IndexCheck,
+        TagIndex,
+    },
+    order::natural_cmp,
+    types::kiln::{
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+    },
+};
+
+use crate::{
+    args::{
+        IndexArgs,
+        IndexCommand,
+        RebuildArgs,
+        VerifyArgs,
+    },
+    commands::set::set_jobs,
+};
+
+pub fn manage_index(args: IndexArgs) -> KilnResult<()> {
+    match args.command {
+        IndexCommand::Rebuild(args) => rebuild_index(args),
+        IndexCommand::Verify(args) => verify_index(args),
+    }
+}
+
+// The index for commands that read tags to use, unless told not to
+pub fn open_index(no_index: bool) -> Option<TagIndex> {
+    match no_index {
+        true => None,
+        false => TagIndex::open_default(),
+    }
+}
+
+// The index only saves time, so a command shouldn't fail just because the
+// index couldn't be loaded or kept up to date
+pub fn save_index(cache: &mut TagCache) {
+    for warning in cache.index_warnings() {
+        eprintln!("{}", warning);
+    }
+    if let Err(e) = cache.save_index() {
+        eprintln!("The index couldn't be saved, so files will be read in full next time: {}", e.message);
+    }
+}
+
+fn default_index() -> KilnResult<TagIndex> {
+    match TagIndex::open_default() {
+        Some(index) => Ok(index),
+        None => Err(KilnError::new(
+            KilnErrorKind::File,
+            "there's nowhere to keep the index, since neither XDG_CACHE_HOME nor HOME is set".to_string()
+        )),
+    }
+}
+
+fn rebuild_index(args: RebuildArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+
+    let glob_string = handle_glob_string(&args.glob);
+    let mut filepaths = get_filepaths_from_glob(&glob_string)?;
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+
+    let mut index = default_index()?;
+    for dir in directories(&filepaths)? {
+        for path in index.prune(&dir) {
+            println!("Dropped {} from the index, since it no longer exists", path.display());
+        }
+    }
+
+    // Reading every file afresh puts it back in the index, hash and all
+    let mut cache = TagCache::new().with_index(Some(index));
+    cache.refresh_all(&filepaths)?;
+    cache.save_index()?;
+
+    println!("Indexed {} files", filepaths.len());
+    Ok(())
+}
+
+fn verify_index(args: VerifyArgs) -> KilnResult<()> {
+    let glob_string = handle_glob_string(&args.glob);
+    let mut filepaths = get_filepaths_from_glob(&glob_string)?;
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+
+    let mut index = default_index()?;
+    let (mut up_to_date, mut out_of_date, mut missing) = (0, 0, 0);
+    let mut problems = Vec::new();
+    for filepath in &filepaths {
+        let path = fs::canonicalize(filepath)?;
+        match index.check(&path)? {
+            IndexCheck::UpToDate => up_to_date += 1,
+            IndexCheck::OutOfDate => out_of_date += 1,
+            IndexCheck::Missing => missing += 1,
+            IndexCheck::Wrong => problems.push(format!("{}: the index doesn't match the file", path.display())),
+        }
+    }
+
+    // Files that are gone do no harm, since nothing will ever look them up
+    for dir in directories(&filepaths)? {
+        for path in index.missing_files(&dir) {
+            println!("{} is in the index, but no longer exists", path.display());
+        }
+    }
+
+    println!("Up to date: {}", up_to_date);
+    println!("Changed since they were indexed: {}", out_of_date);
+    println!("Not indexed: {}", missing);
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let plural = if problems.len() == 1 { "" } else { "s" };
+    Err(KilnError::new(
+        KilnErrorKind::File,
+        format!("the index is wrong about {} file{}, which `kiln index rebuild` will fix:\n{}", problems.len(), plural, problems.join("\n"))
+    ))
+}
+
+// The canonical directories the files are in, each only once
+fn directories(filepaths: &[PathBuf]) -> KilnResult<BTreeSet<PathBuf>> {
+    let mut dirs = BTreeSet::new();
+    for filepath in filepaths {
+        dirs.insert(fs::canonicalize(parent_dir(filepath))?);
+    }
+
+    Ok(dirs)
+}
@@ -1,156 +1,539 @@
-use glob::glob;
-use id3::{
-    Error,
-    ErrorKind,
-    Tag,
-};
+use glob::Pattern;
 use std::{
     collections::HashSet,
-    path::PathBuf,
+    fs,
+    io::{
+        stdout,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use kiln::{
+    files::{
+        construct_shared_tags,
+        get_filepaths_from_glob,
+        handle_glob_string,
+        parent_dir,
+        write_atomically,
+        TagCache,
+    },
+    json::tags_to_json,
+    order::{
+        natural_cmp,
+        sort_tags,
+    },
+    parse::format_tag,
+    query::{
+        filter_paths,
+        parse_query,
+    },
+    table::write_table,
+    types::{
+        json::{
+            JsonDocument,
+            JsonFile,
+            SCHEMA_VERSION,
+        },
+        structured::{
+            StructuredDocument,
+            StructuredSection,
+        },
+        id3::{
+            TagPair,
+            TagSet,
+        },
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            PathMode,
+        },
+    },
 };
 
-use crate::types::{
-    args::ListArgs,
-    id3::{
-        TagPair,
-        TagSet,
+use crate::{
+    args::{
+        Format,
+        FrameOrder,
+        ListArgs,
+        PictureFormat,
     },
-    kiln::{
-        KilnError,
-        KilnErrorKind,
-        KilnResult,
+    commands::{
+        index::{
+            open_index,
+            save_index,
+        },
+        set::set_jobs,
     },
 };
 
 pub fn list_tags(args: ListArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+    let query = args.filter.as_deref().map(parse_query).transpose()?;
+
     let glob_string = handle_glob_string(&args.glob);
-    let filepaths = get_filepaths_from_glob(&glob_string)?;
-    let shared_tags = construct_shared_tags(&filepaths)?;
-    output_tags(&args, &filepaths, &shared_tags)?;
+    let mut filepaths = get_filepaths_from_glob(&glob_string)?;
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+
+    // Every file is read once here, and the output comes from the same cache
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    cache.read_all(&filepaths)?;
+    // A glob section would give its tags to every file the glob matches, not
+    // just the ones the query picked out, so those each list all their tags
+    let shared_tags = match &query {
+        Some(query) => {
+            filepaths = filter_paths(filepaths, query, &mut cache)?;
+            TagSet::new()
+        },
+        None => construct_shared_tags(&filepaths, &mut cache)?,
+    };
+
+    // When writing to a file, headers are relative to it unless told otherwise
+    let base = match (&args.base, &args.output) {
+        (Some(base), _) => Some(fs::canonicalize(base)?),
+        (None, Some(output)) => Some(fs::canonicalize(parent_dir(output))?),
+        (None, None) => None,
+    };
+
+    let output = match args.format {
+        Format::Kiln => output_tags,
+        Format::Json => output_json,
+        Format::Csv => output_csv,
+        Format::Tsv => output_tsv,
+        Format::Yaml => output_yaml,
+        Format::Toml => output_toml,
+    };
 
+    match &args.output {
+        Some(path) => {
+            let mut buf = Vec::new();
+            output(&args, &glob_string, base.as_deref(), &filepaths, &shared_tags, &mut cache, &mut buf)?;
+            write_atomically(path, &buf)?;
+        },
+        None => output(&args, &glob_string, base.as_deref(), &filepaths, &shared_tags, &mut cache, &mut stdout().lock())?,
+    }
+
+    save_index(&mut cache);
     Ok(())
 }
 
-// Very slipshod handling for expanding '~' in globs
-fn handle_glob_string(glob_string: &str) -> String {
-    match &glob_string[..1] {
-        "~" => format!("{}/{}", std::env::var("HOME").unwrap(), &glob_string[1..]),
-        _ => glob_string.to_string()
+fn output_tags(
+    args: &ListArgs,
+    glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    paths_directive(base, out)?;
+
+    if args.filter.is_some() {
+        comment(args, out, "# Only files matching the query are listed, each with all of its tags")?;
+        comment(args, out, "")?;
+    } else if shared_tags.is_empty() && !args.force_empty {
+        comment(args, out, "# No shared tags among files in glob")?;
+        comment(args, out, "")?;
+    } else {
+        comment(args, out, "# All files in glob share the following tags:")?;
+        writeln!(out, "[{}]", glob_header(args, glob_string, base)?)?;
+
+        // Shared tags follow the first file when keeping the order of the file
+        let file_order = match filepaths.first() {
+            Some(filepath) if args.order == FrameOrder::File => cache.frame_order(filepath)?,
+            _ => Vec::new(),
+        };
+        for tag in sort_tags(shared_tags, args.order.into(), &file_order) {
+            output_tag(tag, out)?;
+        }
+        writeln!(out)?;
     }
+
+    let mut no_tags = true;
+    for filepath in filepaths {
+        let tag_set = cache.tag_set(filepath)?;
+        if !tag_set.is_empty() {
+            no_tags = false;
+        }
+
+        let diff_tags = tag_set.difference(shared_tags)
+            .collect::<HashSet<_>>();
+        if !diff_tags.is_empty() || args.force_empty {
+            match args.filter {
+                Some(_) => comment(args, out, "# The following file has these tags:")?,
+                None => comment(args, out, "# The following file has these differing tags:")?,
+            }
+            // Escape the path so that set doesn't treat it as a glob
+            let path_string = file_header(filepath, base)?;
+            writeln!(out, "[{}]", Pattern::escape(&path_string))?;
+            let file_order = match args.order {
+                FrameOrder::File => cache.frame_order(filepath)?,
+                _ => Vec::new(),
+            };
+            for tag in sort_tags(diff_tags, args.order.into(), &file_order) {
+                output_tag(tag, out)?;
+            }
+            writeln!(out)?;
+        }
+    }
+
+    if no_tags && !args.force_empty {
+        comment(args, out, "# No tags among files in glob")?;
+        comment(args, out, "")?;
+    }
+
+    Ok(())
 }
 
-fn get_filepaths_from_glob(glob_string: &str) -> KilnResult<Vec<PathBuf>> {
-    let mut filepaths = Vec::new();
+// Unlike the kiln format, every file is listed, and paths are never escaped
+fn output_json(
+    args: &ListArgs,
+    glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    let embed_pictures = args.pictures == PictureFormat::Base64;
+
+    let mut files = Vec::new();
+    for filepath in filepaths {
+        let tag_set = cache.tag_set(filepath)?;
+        let diff_tags = tag_set.difference(shared_tags)
+            .cloned()
+            .collect::<TagSet>();
+
+        files.push(JsonFile {
+            path: file_header(filepath, base)?,
+            tags: tags_to_json(&diff_tags, embed_pictures),
+        });
+    }
 
-    let entries = match glob(glob_string) {
-        Ok(entries) => entries,
-        Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+    let document = JsonDocument {
+        version: SCHEMA_VERSION,
+        paths: path_mode(base),
+        glob: match args.filter {
+            Some(_) => None,
+            None => Some(glob_header(args, glob_string, base)?),
+        },
+        shared: tags_to_json(shared_tags, embed_pictures),
+        files,
     };
 
-    for entry in entries {
-        let entry = match entry {
-            Ok(entry) => entry,
-            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+    if let Err(e) = serde_json::to_writer_pretty(&mut *out, &document) {
+        return Err(KilnError::new(KilnErrorKind::File, e.to_string()));
+    }
+    writeln!(out)?;
+
+    Ok(())
+}
+
+fn output_csv(
+    _args: &ListArgs,
+    _glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    _shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    output_table(base, filepaths, cache, b',', out)
+}
+
+fn output_tsv(
+    _args: &ListArgs,
+    _glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    _shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    output_table(base, filepaths, cache, b'\t', out)
+}
+
+// Tables list every tag for every file, rather than splitting out shared tags.
+// They can't say what their paths are relative to, so without a base the
+// paths are absolute
+fn output_table(
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    cache: &mut TagCache,
+    delimiter: u8,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    let mut rows = Vec::new();
+    for filepath in filepaths {
+        let path_string = match base {
+            Some(_) => file_header(filepath, base)?,
+            None => absolute_path(filepath)?,
         };
+        rows.push((path_string, cache.tag_set(filepath)?));
+    }
+
+    write_table(&rows, delimiter, out)
+}
 
-        if let Some(ext) = entry.extension() {
-            // For now we only want to operate on mp3 files.
-            if ext != "mp3" { continue; }
+fn output_yaml(
+    args: &ListArgs,
+    glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    let document = structured_document(args, glob_string, base, filepaths, shared_tags, cache)?;
 
-            filepaths.push(entry);
-        }
+    match serde_yaml::to_writer(out, &document) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::File, e.to_string())),
     }
+}
+
+fn output_toml(
+    args: &ListArgs,
+    glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    shared_tags: &TagSet,
+    cache: &mut TagCache,
+    out: &mut dyn Write,
+) -> KilnResult<()> {
+    let document = structured_document(args, glob_string, base, filepaths, shared_tags, cache)?;
 
-    Ok(filepaths)
+    match toml::to_string(&document) {
+        Ok(toml) => Ok(write!(out, "{}", toml)?),
+        Err(e) => Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+    }
 }
 
-fn construct_shared_tags(filepaths: &Vec<PathBuf>) -> KilnResult<TagSet> {
-    if filepaths.is_empty() {
-        return Ok(HashSet::new());
+// The same sections that output_tags prints, just in a structured form
+fn structured_document(
+    args: &ListArgs,
+    glob_string: &str,
+    base: Option<&Path>,
+    filepaths: &Vec<PathBuf>,
+    shared_tags: &TagSet,
+    cache: &mut TagCache,
+) -> KilnResult<StructuredDocument> {
+    let embed_pictures = args.pictures == PictureFormat::Base64;
+    let mut sections = Vec::new();
+
+    if args.filter.is_none() && (!shared_tags.is_empty() || args.force_empty) {
+        sections.push(StructuredSection {
+            glob: Some(glob_header(args, glob_string, base)?),
+            path: None,
+            tags: tags_to_json(shared_tags, embed_pictures),
+        });
     }
 
-    let mut tag_sets = Vec::new();
     for filepath in filepaths {
-        let tag = match Tag::read_from_path(filepath) {
-            Ok(tag) => tag,
-            Err(Error { kind: ErrorKind::NoTag, .. }) => Tag::new(),
-            Err(e) => return Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
-        };
+        let tag_set = cache.tag_set(filepath)?;
+        let diff_tags = tag_set.difference(shared_tags)
+            .cloned()
+            .collect::<TagSet>();
 
-        let mut tag_set = HashSet::new();
-        for frame in tag.frames() {
-            let tag_pair = match TagPair::from_str_with_content(frame.id(), frame.content().clone()) {
-                Ok(tag_pair) => tag_pair,
-                Err(e) => return Err(e),
-            };
-            tag_set.insert(tag_pair);
+        if !diff_tags.is_empty() || args.force_empty {
+            sections.push(StructuredSection {
+                glob: None,
+                path: Some(file_header(filepath, base)?),
+                tags: tags_to_json(&diff_tags, embed_pictures),
+            });
         }
+    }
+
+    Ok(StructuredDocument { version: SCHEMA_VERSION, paths: path_mode(base), sections })
+}
 
-        tag_sets.push(tag_set);
+// Headers are relative to the working directory when there's no base, so the
+// output has to say so, or set would resolve them against the file instead
+pub fn paths_directive(base: Option<&Path>, out: &mut dyn Write) -> KilnResult<()> {
+    if base.is_none() {
+        writeln!(out, "@paths = cwd\n")?;
     }
 
-    let (intersection, others) = tag_sets.split_at_mut(1);
-    let intersection = &mut intersection[0];
-    for other in others {
-        intersection.retain(|e| other.contains(e));
+    Ok(())
+}
+
+fn path_mode(base: Option<&Path>) -> Option<PathMode> {
+    match base {
+        Some(_) => None,
+        None => Some(PathMode::Cwd),
     }
+}
 
-    Ok(intersection.clone())
+fn glob_header(args: &ListArgs, glob_string: &str, base: Option<&Path>) -> KilnResult<String> {
+    match base {
+        Some(base) => Ok(relative_to(Path::new(glob_string), base)?.display().to_string()),
+        None => Ok(args.glob.clone()),
+    }
 }
 
-fn output_tags(args: &ListArgs, filepaths: &Vec<PathBuf>, shared_tags: &TagSet) -> KilnResult<()> {
-    if shared_tags.is_empty() && !args.force_empty {
-        comment(args, "# No shared tags among files in glob");
-        comment(args, "");
-    } else {
-        comment(args, "# All files in glob share the following tags:");
-        println!("[{}]", args.glob);
-        for tag in shared_tags {
-            println!("{:?} = {}", tag.id, tag.val);
-        }
-        println!("");
+pub fn file_header(filepath: &Path, base: Option<&Path>) -> KilnResult<String> {
+    let filepath = match base {
+        Some(base) => relative_to(filepath, base)?,
+        None => filepath.to_path_buf(),
+    };
+
+    Ok(filepath.into_os_string().into_string().unwrap())
+}
+
+fn absolute_path(filepath: &Path) -> KilnResult<String> {
+    match std::path::absolute(filepath) {
+        Ok(path) => Ok(path.display().to_string()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::File, e.to_string())),
     }
+}
 
-    let mut no_tags = true;
-    for filepath in filepaths {
-        let tag = match Tag::read_from_path(filepath) {
-            Ok(tag) => tag,
-            Err(Error { kind: ErrorKind::NoTag, .. }) => Tag::new(),
-            Err(e) => return Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
-        };
+// Express a path relative to the given (canonical) base directory, walking up
+// out of the base with '..' where necessary
+fn relative_to(path: &Path, base: &Path) -> KilnResult<PathBuf> {
+    let path = match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+    };
 
-        let mut tag_set = HashSet::new();
-        for frame in tag.frames() {
-            no_tags = false;
-            let tag_pair = match TagPair::from_str_with_content(frame.id(), frame.content().clone()) {
-                Ok(tag_pair) => tag_pair,
-                Err(e) => return Err(e),
-            };
-            tag_set.insert(tag_pair);
-        }
+    let path_components = path.components().collect::<Vec<_>>();
+    let base_components = base.components().collect::<Vec<_>>();
+    let common = path_components.iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
 
-        let diff_tags = tag_set.difference(shared_tags)
-            .collect::<HashSet<_>>();
-        if !diff_tags.is_empty() || args.force_empty {
-            comment(args, "# The following file has these differing tags:");
-            println!("[{}]", filepath.clone().into_os_string().into_string().unwrap());
-            for tag in diff_tags {
-                println!("{:?} = {}", tag.id, tag.val);
-            }
-            println!("");
-        }
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &path_components[common..] {
+        relative.push(component);
     }
 
-    if no_tags && !args.force_empty {
-        comment(args, "# No tags among files in glob");
-        comment(args, "");
+    Ok(relative)
+}
+
+pub fn output_tag(tag: &TagPair, out: &mut dyn Write) -> KilnResult<()> {
+    for line in format_tag(tag) {
+        writeln!(out, "{}", line)?;
     }
 
     Ok(())
 }
 
-fn comment(args: &ListArgs, string: &str) {
+fn comment(args: &ListArgs, out: &mut dyn Write, string: &str) -> KilnResult<()> {
     if !args.no_comments {
-        println!("{string}");
+        writeln!(out, "{string}")?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use id3::{
+        frame::{
+            Comment,
+            ExtendedText,
+        },
+        Tag,
+        TagLike,
+        Version,
+    };
+    use kiln::{
+        input::read_input,
+        plan::calculate_diff,
+    };
+
+    use super::*;
+    use crate::args::{
+        Commands,
+        KilnArgs,
+    };
+
+    fn list_args(args: &[&str]) -> ListArgs {
+        match KilnArgs::parse_from([&["kiln", "list", "--no-index"], args].concat()).command {
+            Commands::List(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    // Both files share an album, but only one matches the query, so a glob
+    // section would give the album to the other as well
+    #[test]
+    fn filtered_files_get_no_glob_section() {
+        let dir = std::env::temp_dir().join(format!("kiln-list-where-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, title) in [("a.mp3", Some("Kept")), ("b.mp3", None)] {
+            let mut tag = Tag::new();
+            tag.set_album("Shared");
+            if let Some(title) = title {
+                tag.set_title(title);
+            }
+            fs::write(dir.join(name), []).unwrap();
+            tag.write_to_path(dir.join(name), Version::Id3v24).unwrap();
+        }
+
+        let glob = format!("{}/*", Pattern::escape(&dir.to_string_lossy()));
+        for (format, output) in [("kiln", "out.kiln"), ("json", "out.json"), ("yaml", "out.yaml")] {
+            let output = dir.join(output);
+            let output_string = output.to_string_lossy().to_string();
+            list_tags(list_args(&["--where", "has(TIT2)", "--format", format, "-o", &output_string, &glob])).unwrap();
+
+            let content = fs::read_to_string(&output).unwrap();
+            assert!(!content.contains('*'), "{}", content);
+            assert!(content.contains("a.mp3") && !content.contains("b.mp3"), "{}", content);
+            assert!(content.contains("Shared"), "{}", content);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Whatever list prints has to read back in as the very same tags, even
+    // for files with several comments and TXXX frames
+    #[test]
+    fn listed_tags_set_back_without_changes() {
+        let dir = std::env::temp_dir().join(format!("kiln-list-round-trip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, title) in [("a.mp3", "One"), ("b.mp3", "Two")] {
+            let comment = |lang: &str, description: &str, text: &str| Comment {
+                lang: lang.to_string(),
+                description: description.to_string(),
+                text: text.to_string(),
+            };
+            let extended = |description: &str, value: &str| ExtendedText {
+                description: description.to_string(),
+                value: value.to_string(),
+            };
+
+            let mut tag = Tag::new();
+            tag.set_title(title);
+            tag.set_album("Shared");
+            tag.add_frame(comment("eng", "", "hi"));
+            tag.add_frame(comment("eng", "iTunNORM", &format!("0000 {}", title)));
+            tag.add_frame(comment("deu", "", "Hallo: Welt"));
+            tag.add_frame(extended("GAIN", "-6 dB"));
+            tag.add_frame(extended("", "Note: this"));
+            fs::write(dir.join(name), []).unwrap();
+            tag.write_to_path(dir.join(name), Version::Id3v24).unwrap();
+        }
+
+        let glob = format!("{}/*", Pattern::escape(&dir.to_string_lossy()));
+        let output = dir.join("out.kiln");
+        list_tags(list_args(&["-o", &output.to_string_lossy(), &glob])).unwrap();
+
+        let document = read_input(&output, None, false, false).unwrap();
+        let diff = calculate_diff(document.sections, Vec::new(), &mut TagCache::new()).unwrap();
+        let content = fs::read_to_string(&output).unwrap();
+        assert_eq!(diff.len(), 2, "{}", content);
+        assert!(diff.iter().all(|e| e.diffs.is_empty()), "{}", content);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
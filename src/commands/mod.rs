@@ -1,2 +1,13 @@
+pub mod apply;
+pub mod compare;
+pub mod copy;
+pub mod diff;
+pub mod from_path;
+pub mod index;
 pub mod list;
+pub mod normalize;
+pub mod plan;
+pub mod rename;
+pub mod replace;
+pub mod search;
 pub mod set;
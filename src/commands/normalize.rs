@@ -0,0 +1,115 @@
+use glob::Pattern;
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        parent_dir,
+        TagCache,
+    },
+    normalize::{
+        normalize_tag_set,
+        read_rules,
+        track_total,
+    },
+    order::sort_diff,
+    plan::diff_tag_sets,
+    types::{
+        id3::{
+            TagId,
+            TagSet,
+        },
+        kiln::KilnResult,
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::NormalizeArgs,
+    commands::{
+        index::{
+            open_index,
+            save_index,
+        },
+        search::selected_files,
+        set::{
+            apply_diff,
+            set_jobs,
+        },
+    },
+};
+
+// An album's directory and name
+type AlbumKey = (PathBuf, Vec<String>);
+
+pub fn normalize_tags(args: NormalizeArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+    let rules = read_rules(&args.rules)?;
+    let filepaths = selected_files(&args.glob, args.recursive)?;
+
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    cache.read_all(&filepaths)?;
+
+    let track_totals = match rules.track.as_ref().is_some_and(|e| e.total) {
+        true => album_track_totals(&filepaths, &mut cache)?,
+        false => HashMap::new(),
+    };
+
+    let mut diff = Vec::new();
+    for filepath in &filepaths {
+        let old_set = cache.tag_set(filepath)?;
+        let track_total = track_totals.get(&album_key(filepath, &old_set)).copied().flatten();
+        let new_set = normalize_tag_set(&old_set, &rules, track_total);
+
+        let filediff = diff_tag_sets(filepath.to_string_lossy().to_string(), &old_set, &new_set, &[]);
+        if !filediff.diffs.is_empty() {
+            diff.push(filediff);
+        }
+    }
+
+    sort_diff(&mut diff, FrameOrder::default(), &mut cache)?;
+    apply_diff(diff, args.ask, false, args.id3_version.into(), &mut cache)?;
+    save_index(&mut cache);
+    Ok(())
+}
+
+// Files in the same directory with the same album are taken to be one album.
+// Every file in the directory counts, not just the selected ones, so that a
+// glob picking out a few tracks doesn't change the total.
+fn album_track_totals(
+    filepaths: &[PathBuf],
+    cache: &mut TagCache,
+) -> KilnResult<HashMap<AlbumKey, Option<usize>>> {
+    let mut dirs = filepaths.iter().map(|e| parent_dir(e)).collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+
+    let mut album_files = Vec::new();
+    for dir in dirs {
+        let dir_glob = Path::new(&Pattern::escape(&dir.to_string_lossy())).join("*.mp3");
+        album_files.extend(get_filepaths_from_glob(&dir_glob.to_string_lossy())?);
+    }
+    cache.read_all(&album_files)?;
+
+    let mut albums = HashMap::new();
+    for filepath in &album_files {
+        let tag_set = cache.tag_set(filepath)?;
+        albums.entry(album_key(filepath, &tag_set)).or_insert_with(Vec::new).push(tag_set);
+    }
+
+    Ok(albums.into_iter().map(|(key, album)| (key, track_total(&album))).collect())
+}
+
+fn album_key(filepath: &Path, tag_set: &TagSet) -> AlbumKey {
+    let album = tag_set.iter()
+        .find(|e| e.id == TagId::TALB)
+        .map(|e| e.values())
+        .unwrap_or_default();
+    (parent_dir(filepath).to_path_buf(), album)
+}
@@ -0,0 +1,56 @@
+use std::io::{
+    stdout,
+    Write,
+};
+
+use kiln::{
+    files::{
+        write_atomically,
+        TagCache,
+    },
+    order::sort_diff,
+    plan::{
+        calculate_diff,
+        make_plan,
+    },
+    query::{
+        filter_diff,
+        parse_query,
+    },
+    types::{
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+        },
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::PlanArgs,
+    commands::set::read_input_args,
+};
+
+pub fn plan_changes(args: PlanArgs) -> KilnResult<()> {
+    let query = args.input.filter.as_deref().map(parse_query).transpose()?;
+    let (sections, preserved_tags) = read_input_args(&args.input)?;
+    let mut cache = TagCache::new();
+    let mut diff = calculate_diff(sections, preserved_tags, &mut cache)?;
+    if let Some(query) = &query {
+        diff = filter_diff(diff, query, &mut cache)?;
+    }
+    sort_diff(&mut diff, FrameOrder::default(), &mut cache)?;
+    let plan = make_plan(&diff, true, &mut cache)?;
+
+    let mut buf = match serde_json::to_vec_pretty(&plan) {
+        Ok(buf) => buf,
+        Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+    };
+    buf.push(b'\n');
+
+    match &args.output {
+        Some(path) => write_atomically(path, &buf),
+        None => Ok(stdout().lock().write_all(&buf)?),
+    }
+}
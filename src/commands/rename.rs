@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        handle_glob_string,
+        path_errors,
+        write_atomically,
+        TagCache,
+    },
+    types::{
+        id3::{
+            TagId,
+            TagSet,
+        },
+        json::{
+            JsonRename,
+            JsonUndoLog,
+            SCHEMA_VERSION,
+        },
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+        },
+    },
+};
+
+use crate::{
+    args::RenameArgs,
+    commands::{
+        index::{
+            open_index,
+            save_index,
+        },
+        set::{
+            get_user_confirmation,
+            set_jobs,
+        },
+    },
+};
+
+enum Piece {
+    Literal(String),
+    Tag(TagId, Option<usize>),
+    Ext,
+}
+
+pub fn rename_files(args: RenameArgs) -> KilnResult<()> {
+    if let Some(undo) = &args.undo {
+        return undo_renames(&args, undo);
+    }
+
+    set_jobs(args.jobs)?;
+    let template = parse_template(args.template.as_deref().unwrap_or_default())?;
+    let filepaths = get_filepaths_from_glob(&handle_glob_string(&args.glob))?;
+
+    // Files that can't be read are reported one at a time below, and the rest
+    // are renamed regardless
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    let _ = cache.read_all(&filepaths);
+    save_index(&mut cache);
+
+    let mut renames = Vec::new();
+    let mut errors = Vec::new();
+    for filepath in filepaths {
+        let tag_set = match cache.tag_set(&filepath) {
+            Ok(tag_set) => tag_set,
+            Err(e) => {
+                errors.push((filepath, e));
+                continue;
+            },
+        };
+        let name = match render(&template, &filepath, &tag_set) {
+            Ok(name) => name,
+            Err(id) => {
+                eprintln!("{} has no {:?} to name it with, skipping it", filepath.display(), id);
+                continue;
+            },
+        };
+
+        let from = normalize(&filepath);
+        let to = normalize(&args.dest.join(name));
+        if from != to {
+            renames.push((from, to));
+        }
+    }
+
+    check_collisions(&renames)?;
+
+    if !confirm(&args, &renames)? {
+        return path_errors(errors, "read");
+    }
+
+    // One file failing to move doesn't stop the rest
+    let mut log = JsonUndoLog { version: SCHEMA_VERSION, renames: Vec::new() };
+    let mut move_errors = Vec::new();
+    for (from, to) in &renames {
+        // The log holds absolute paths, so it can be undone from anywhere
+        let res = fs::canonicalize(from)
+            .map_err(KilnError::from)
+            .and_then(|from| {
+                move_file(&from, to)?;
+                log.renames.push(JsonRename { from, to: fs::canonicalize(to)? });
+                Ok(())
+            });
+        if let Err(e) = res {
+            move_errors.push((from.clone(), e));
+        }
+    }
+
+    // Even if something went wrong part way through, we still want to be able
+    // to undo whatever was already renamed
+    if let Some(path) = &args.undo_log {
+        match serde_json::to_vec_pretty(&log) {
+            Ok(buf) => write_atomically(path, &buf)?,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+        }
+    }
+
+    path_errors(errors, "read")?;
+    path_errors(move_errors, "renamed")
+}
+
+fn undo_renames(args: &RenameArgs, undo: &Path) -> KilnResult<()> {
+    let content = fs::read_to_string(undo)?;
+    let log: JsonUndoLog = match serde_json::from_str(&content) {
+        Ok(log) => log,
+        Err(e) => return Err(KilnError::new(KilnErrorKind::Parse, format!("{}: {}", undo.display(), e))),
+    };
+
+    // Undo them in the opposite order, in case one rename depended on another
+    let renames = log.renames.into_iter()
+        .rev()
+        .map(|e| (e.to, e.from))
+        .collect::<Vec<_>>();
+
+    check_collisions(&renames)?;
+
+    if !confirm(args, &renames)? {
+        return Ok(());
+    }
+
+    for (from, to) in &renames {
+        move_file(from, to)?;
+
+        // Clean up any directories that were only made to hold renamed files
+        let mut parent = from.parent();
+        while let Some(dir) = parent {
+            if fs::remove_dir(dir).is_err() { break; }
+            parent = dir.parent();
+        }
+    }
+
+    Ok(())
+}
+
+// Print the renames we're about to make, and check whether we should go ahead
+fn confirm(args: &RenameArgs, renames: &[(PathBuf, PathBuf)]) -> KilnResult<bool> {
+    if renames.is_empty() {
+        println!("No files to rename, exiting...");
+        return Ok(false);
+    }
+
+    for (from, to) in renames {
+        println!("{} -> {}", from.display(), to.display());
+    }
+
+    if args.dry_run {
+        return Ok(false);
+    }
+
+    if args.ask {
+        match &get_user_confirmation("Allow the above files to be renamed?", false)?[..] {
+            "y" | "yes" | "" => {},
+            _ => {
+                println!("No files will be renamed. Exiting...");
+                return Ok(false);
+            }
+            // Anything that isn't "Yes" is "No"
+        }
+    }
+
+    Ok(true)
+}
+
+fn move_file(from: &Path, to: &Path) -> KilnResult<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_and_remove(from, to),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Files can't be renamed onto another file system, so they're copied there
+// instead, and the original is only removed once the copy is complete
+fn copy_and_remove(from: &Path, to: &Path) -> KilnResult<()> {
+    if let Err(e) = fs::copy(from, to) {
+        let _ = fs::remove_file(to);
+        return Err(KilnError::new(KilnErrorKind::File, format!("couldn't copy it to {}: {}", to.display(), e)));
+    }
+
+    Ok(fs::remove_file(from)?)
+}
+
+// Nothing gets renamed unless every file has somewhere of its own to go
+fn check_collisions(renames: &[(PathBuf, PathBuf)]) -> KilnResult<()> {
+    let mut targets: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for (from, to) in renames {
+        targets.entry(to).or_default().push(from);
+    }
+
+    let mut collisions = Vec::new();
+    for (from, to) in renames {
+        if to.exists() {
+            collisions.push(format!("{} -> {} already exists", from.display(), to.display()));
+        }
+    }
+    for (to, froms) in &targets {
+        if froms.len() > 1 {
+            let froms = froms.iter().map(|e| e.display().to_string()).collect::<Vec<_>>();
+            collisions.push(format!("{} would all be renamed to {}", froms.join(", "), to.display()));
+        }
+    }
+
+    if collisions.is_empty() {
+        return Ok(());
+    }
+
+    collisions.sort();
+    Err(KilnError::new(
+        KilnErrorKind::File,
+        format!("no files were renamed, because some of them collide:\n{}", collisions.join("\n"))
+    ))
+}
+
+// Templates are made up of literal text and placeholders like '{TIT2}',
+// '{TRCK:02}' or '{ext}', with '{{' and '}}' standing for literal braces
+fn parse_template(template: &str) -> KilnResult<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); literal.push('{'); },
+            '}' if chars.peek() == Some(&'}') => { chars.next(); literal.push('}'); },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(KilnError::new(
+                            KilnErrorKind::Parse,
+                            format!("{{{} is missing its closing '}}'", placeholder)
+                        )),
+                    }
+                }
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                pieces.push(parse_placeholder(&placeholder)?);
+            },
+            c => literal.push(c),
+        }
+    }
+    pieces.push(Piece::Literal(literal));
+
+    Ok(pieces)
+}
+
+fn parse_placeholder(placeholder: &str) -> KilnResult<Piece> {
+    let (name, width) = match placeholder.split_once(':') {
+        Some((name, width)) => match width.parse() {
+            Ok(width) => (name, Some(width)),
+            Err(_) => return Err(KilnError::new(KilnErrorKind::Parse, format!("invalid width in {{{}}}", placeholder))),
+        },
+        None => (placeholder, None),
+    };
+
+    if name == "ext" {
+        return Ok(Piece::Ext);
+    }
+
+    match name.parse::<TagId>() {
+        Ok(TagId::APIC) => Err(KilnError::new(KilnErrorKind::Parse, "APIC cannot be used in a file name".to_string())),
+        Ok(TagId::TXXX) => Err(KilnError::new(KilnErrorKind::Parse, "TXXX cannot be used in a file name, since a file can have several".to_string())),
+        Ok(id) => Ok(Piece::Tag(id, width)),
+        Err(_) => Err(KilnError::new(KilnErrorKind::ID3, format!("{} is not a valid id3 tag for kiln", name))),
+    }
+}
+
+// Fill in the template for a file, or give back the first tag it's missing
+fn render(template: &[Piece], filepath: &Path, tag_set: &TagSet) -> Result<String, TagId> {
+    let mut name = String::new();
+
+    for piece in template {
+        match piece {
+            Piece::Literal(literal) => name.push_str(literal),
+            Piece::Ext => name.push_str(&filepath.extension().unwrap_or_default().to_string_lossy()),
+            Piece::Tag(id, width) => {
+                let value = match tag_set.iter().find(|e| e.id == *id) {
+                    Some(tag) => sanitize(&pad(&tag.values().join(", "), *width)),
+                    None => return Err(*id),
+                };
+                if value.is_empty() {
+                    return Err(*id);
+                }
+                name.push_str(&value);
+            },
+        }
+    }
+
+    Ok(name)
+}
+
+// Numbers like track numbers are padded with zeros, taking only the first
+// part of something like "3/12"
+fn pad(value: &str, width: Option<usize>) -> String {
+    let number = value.split('/').next().unwrap_or_default().trim();
+
+    match width {
+        Some(width) if !number.is_empty() && number.chars().all(|e| e.is_ascii_digit()) => {
+            format!("{:0>width$}", number, width = width)
+        },
+        _ => value.to_string(),
+    }
+}
+
+// Tag values shouldn't be able to add directories, or use characters that
+// some file systems won't allow in a name
+fn sanitize(value: &str) -> String {
+    let value = value.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    value.trim().trim_end_matches('.').to_string()
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|e| *e != Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use kiln::types::id3::TagPair;
+
+    use super::*;
+
+    fn tags(frames: &[(&str, &str)]) -> TagSet {
+        frames.iter().map(|(id, val)| TagPair::from_str(id, val).unwrap()).collect()
+    }
+
+    fn rendered(template: &str, frames: &[(&str, &str)]) -> Result<String, TagId> {
+        render(&parse_template(template).unwrap(), Path::new("music/track.mp3"), &tags(frames))
+    }
+
+    fn template_error(template: &str) -> String {
+        match parse_template(template) {
+            Ok(_) => panic!("{} should not parse", template),
+            Err(e) => e.message,
+        }
+    }
+
+    #[test]
+    fn templates_fill_in_tags_and_keep_literal_braces() {
+        let frames = [("TRCK", "3/12"), ("TIT2", "Opal Drifters"), ("TPE1", "Maps\0Opal")];
+        assert_eq!(rendered("{TRCK:02} {TIT2}.{ext}", &frames).unwrap(), "03 Opal Drifters.mp3");
+        assert_eq!(rendered("{{{TRCK}}} {TPE1}", &frames).unwrap(), "{3_12} Maps, Opal");
+        assert_eq!(rendered("no placeholders", &frames).unwrap(), "no placeholders");
+        assert_eq!(rendered("{TALB}/{TIT2}", &frames), Err(TagId::TALB));
+    }
+
+    #[test]
+    fn bad_templates_are_refused() {
+        assert!(template_error("{TIT2").contains("missing its closing '}'"));
+        assert!(template_error("{TRCK:02} {TIT2").contains("{TIT2 is missing"));
+        assert!(template_error("{TIT2}{").contains("missing its closing '}'"));
+        assert!(template_error("{TRCK:two}").contains("invalid width"));
+        assert!(template_error("{APIC}").contains("APIC cannot be used"));
+        assert!(template_error("{TXXX}").contains("TXXX cannot be used"));
+        assert!(template_error("{NAME}").contains("NAME is not a valid id3 tag"));
+    }
+
+    #[test]
+    fn only_plain_numbers_are_padded() {
+        assert_eq!(pad("3/12", Some(2)), "03");
+        assert_eq!(pad("3", Some(3)), "003");
+        assert_eq!(pad("123", Some(2)), "123");
+        assert_eq!(pad("3", None), "3");
+        assert_eq!(pad("Side A", Some(2)), "Side A");
+        assert_eq!(pad("", Some(2)), "");
+    }
+
+    #[test]
+    fn values_cannot_add_directories_or_odd_characters() {
+        assert_eq!(sanitize("AC/DC"), "AC_DC");
+        assert_eq!(sanitize("..\\up"), ".._up");
+        assert_eq!(sanitize("What? Now: \"this\" <|> *"), "What_ Now_ _this_ ___ _");
+        assert_eq!(sanitize("tab\there"), "tab_here");
+        assert_eq!(sanitize("  Trailing dots... "), "Trailing dots");
+
+        // A value with nothing left after sanitizing can't name a file
+        assert_eq!(rendered("{TIT2}.{ext}", &[("TIT2", "...")]), Err(TagId::TIT2));
+    }
+
+    #[test]
+    fn colliding_renames_are_all_reported() {
+        let dir = std::env::temp_dir().join(format!("kiln-rename-collisions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("taken.mp3"), b"").unwrap();
+
+        let rename = |from: &str, to: &str| (dir.join(from), dir.join(to));
+        assert!(check_collisions(&[rename("a.mp3", "b.mp3"), rename("b.mp3", "c.mp3")]).is_ok());
+
+        let e = check_collisions(&[
+            rename("a.mp3", "same.mp3"),
+            rename("b.mp3", "same.mp3"),
+            rename("c.mp3", "taken.mp3"),
+        ]).unwrap_err();
+        assert!(e.message.starts_with("no files were renamed"));
+        assert!(e.message.contains("would all be renamed to"));
+        assert!(e.message.contains("taken.mp3 already exists"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_copied_across_file_systems_are_removed_afterwards() {
+        let dir = std::env::temp_dir().join(format!("kiln-rename-copy-{}", std::process::id()));
+        fs::create_dir_all(dir.join("to")).unwrap();
+        fs::write(dir.join("from.mp3"), b"audio").unwrap();
+
+        copy_and_remove(&dir.join("from.mp3"), &dir.join("to/moved.mp3")).unwrap();
+        assert!(!dir.join("from.mp3").exists());
+        assert_eq!(fs::read(dir.join("to/moved.mp3")).unwrap(), b"audio");
+
+        // A failed copy leaves the original where it was
+        fs::write(dir.join("from.mp3"), b"audio").unwrap();
+        assert!(copy_and_remove(&dir.join("from.mp3"), &dir.join("missing/moved.mp3")).is_err());
+        assert!(dir.join("from.mp3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
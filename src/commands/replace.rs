@@ -0,0 +1,136 @@
+use id3::frame::Content;
+use regex::{
+    NoExpand,
+    Regex,
+};
+
+use kiln::{
+    files::TagCache,
+    order::sort_diff,
+    plan::diff_tag_sets,
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+            TagSet,
+        },
+        kiln::KilnResult,
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::ReplaceArgs,
+    commands::{
+        index::{
+            open_index,
+            save_index,
+        },
+        search::{
+            pattern_regex,
+            selected_files,
+        },
+        set::{
+            apply_diff,
+            set_jobs,
+        },
+    },
+};
+
+pub fn replace_tags(args: ReplaceArgs) -> KilnResult<()> {
+    set_jobs(args.jobs)?;
+    let regex = pattern_regex(&args.pattern, args.regex, args.ignore_case)?;
+    let filepaths = selected_files(&args.glob, args.recursive)?;
+
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    cache.read_all(&filepaths)?;
+
+    let mut diff = Vec::new();
+    for filepath in &filepaths {
+        let old_set = cache.tag_set(filepath)?;
+        let new_set = old_set.iter()
+            .filter_map(|tag| replace_in_tag(tag, &regex, &args))
+            .collect::<TagSet>();
+
+        let filediff = diff_tag_sets(filepath.to_string_lossy().to_string(), &old_set, &new_set, &[]);
+        if !filediff.diffs.is_empty() {
+            diff.push(filediff);
+        }
+    }
+
+    sort_diff(&mut diff, FrameOrder::default(), &mut cache)?;
+    apply_diff(diff, args.ask, false, args.id3_version.into(), &mut cache)?;
+    save_index(&mut cache);
+    Ok(())
+}
+
+// Only text frames are changed, since comments and pictures aren't just text.
+// Values left empty are dropped, and so is a frame left with none at all
+fn replace_in_tag(tag: &TagPair, regex: &Regex, args: &ReplaceArgs) -> Option<TagPair> {
+    let replaced = |id: &TagId| args.fields.is_empty() || args.fields.contains(id);
+    if !replaced(&tag.id) || !matches!(tag.val, Content::Text(_)) {
+        return Some(tag.clone());
+    }
+
+    let values = tag.values().iter()
+        .map(|val| match args.regex {
+            true => regex.replace_all(val, args.replacement.as_str()).to_string(),
+            false => regex.replace_all(val, NoExpand(&args.replacement)).to_string(),
+        })
+        .filter(|val| !val.is_empty())
+        .collect::<Vec<_>>();
+
+    match values.is_empty() {
+        true => None,
+        false => Some(TagPair::from_id(tag.id, Content::Text(values.join("\0")))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::{
+        Commands,
+        KilnArgs,
+    };
+
+    fn replaced(args: &[&str], id: &str, val: &str) -> Option<String> {
+        let args = match KilnArgs::parse_from([&["kiln", "replace"], args].concat()).command {
+            Commands::Replace(args) => args,
+            _ => unreachable!(),
+        };
+        let regex = pattern_regex(&args.pattern, args.regex, args.ignore_case).unwrap();
+        let tag = TagPair::from_str(id, val).unwrap();
+        replace_in_tag(&tag, &regex, &args).map(|tag| tag.values().join("\0"))
+    }
+
+    #[test]
+    fn regex_replacements_expand_groups() {
+        let swap = ["--regex", r"^(\w+) (\w+)$", "$2 $1"];
+        assert_eq!(replaced(&swap, "TIT2", "Opal Drifters").unwrap(), "Drifters Opal");
+        assert_eq!(replaced(&["--regex", r"(\d+)", "[$1]"], "TIT2", "Track 3 of 12").unwrap(), "Track [3] of [12]");
+        assert_eq!(replaced(&["--regex", "-i", "maps", "MAPS"], "TPE1", "Maps").unwrap(), "MAPS");
+    }
+
+    #[test]
+    fn plain_replacements_are_taken_literally() {
+        assert_eq!(replaced(&["(live)", "$1"], "TIT2", "Opal (live)").unwrap(), "Opal $1");
+        assert_eq!(replaced(&["a.b", "${x}"], "TIT2", "a.b acb").unwrap(), "${x} acb");
+    }
+
+    #[test]
+    fn emptied_values_are_dropped() {
+        assert_eq!(replaced(&["Demo", ""], "TIT2", "Demo"), None);
+        assert_eq!(replaced(&["--regex", ".*", ""], "TPE1", "Maps\0Opal"), None);
+        assert_eq!(replaced(&["Maps", ""], "TPE1", "Maps\0Opal").unwrap(), "Opal");
+    }
+
+    #[test]
+    fn every_value_of_a_text_frame_is_replaced() {
+        assert_eq!(replaced(&["Opal", "Onyx"], "TPE1", "Maps\0Opal\0Opal Duo").unwrap(), "Maps\0Onyx\0Onyx Duo");
+        assert_eq!(replaced(&["-f", "TIT2", "Opal", "Onyx"], "TPE1", "Maps\0Opal").unwrap(), "Maps\0Opal");
+        assert_eq!(replaced(&["-f", "tpe1", "Opal", "Onyx"], "TPE1", "Maps\0Opal").unwrap(), "Maps\0Onyx");
+    }
+}
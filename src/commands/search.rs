@@ -0,0 +1,208 @@
+use colored::Colorize;
+use glob::Pattern;
+use regex::{
+    Regex,
+    RegexBuilder,
+};
+use std::{
+    io::{
+        stdout,
+        Write,
+    },
+    path::PathBuf,
+};
+
+use kiln::{
+    files::{
+        get_filepaths_from_glob,
+        get_filepaths_recursively,
+        handle_glob_string,
+        TagCache,
+    },
+    order::{
+        natural_cmp,
+        sort_tags,
+    },
+    parse::{
+        escape_value,
+        format_tag,
+        needs_quotes,
+        quote_value,
+    },
+    types::{
+        id3::{
+            TagId,
+            TagSet,
+        },
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+        },
+        options::FrameOrder,
+    },
+};
+
+use crate::{
+    args::SearchArgs,
+    commands::{
+        diff::{
+            ignore_broken_pipe,
+            set_color,
+        },
+        index::{
+            open_index,
+            save_index,
+        },
+        set::set_jobs,
+    },
+};
+
+pub fn search_tags(args: SearchArgs) -> KilnResult<()> {
+    set_color(args.color);
+    set_jobs(args.jobs)?;
+    let regex = pattern_regex(&args.pattern, args.regex, args.ignore_case)?;
+
+    let filepaths = selected_files(&args.glob, args.recursive)?;
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    cache.read_all(&filepaths)?;
+
+    let mut out = stdout().lock();
+    let mut res = Ok(());
+    let mut found = false;
+    for filepath in &filepaths {
+        let tag_set = cache.tag_set(filepath)?;
+        let Some(lines) = file_lines(&tag_set, &args.fields, &regex) else { continue; };
+
+        // Headers are relative to the working directory, just like list's
+        if !found {
+            found = true;
+            res = writeln!(out, "@paths = cwd\n");
+            if res.is_err() { break; }
+        }
+
+        let header = Pattern::escape(&filepath.to_string_lossy());
+        res = writeln!(out, "[{}]\n{}\n", header, lines.join("\n"));
+        if res.is_err() { break; }
+    }
+
+    ignore_broken_pipe(res)?;
+    save_index(&mut cache);
+    Ok(())
+}
+
+// The files a glob selects, in natural order, along with every file beneath
+// the directories it selects when recursive
+pub fn selected_files(glob: &str, recursive: bool) -> KilnResult<Vec<PathBuf>> {
+    let glob_string = handle_glob_string(glob);
+    let mut filepaths = match recursive {
+        true => get_filepaths_recursively(&glob_string)?,
+        false => get_filepaths_from_glob(&glob_string)?,
+    };
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+    filepaths.dedup();
+
+    Ok(filepaths)
+}
+
+// Every tag of a file as lines of a kiln file, with the matches picked out,
+// so that nothing is lost if the output is edited and set again. Files with
+// no match in the searched frames give nothing.
+fn file_lines(tag_set: &TagSet, fields: &[TagId], regex: &Regex) -> Option<Vec<String>> {
+    let searched = |id: &TagId| fields.is_empty() || fields.contains(id);
+
+    let mut matched = false;
+    let mut lines = Vec::new();
+    for tag in sort_tags(tag_set, FrameOrder::default(), &[]) {
+        if !searched(&tag.id) || !tag.values().iter().any(|val| regex.is_match(val)) {
+            lines.extend(format_tag(tag));
+            continue;
+        }
+
+        matched = true;
+        for val in tag.values() {
+            match regex.is_match(&val) {
+                true => lines.push(highlight(tag.id, &val, regex)),
+                false => lines.push(format!("{:?} = {}", tag.id, quote_value(&val))),
+            }
+        }
+    }
+
+    match matched {
+        true => Some(lines),
+        false => None,
+    }
+}
+
+// The regex to look for a pattern with, which is taken literally unless it's
+// meant to be a regex
+pub fn pattern_regex(pattern: &str, regex: bool, ignore_case: bool) -> KilnResult<Regex> {
+    let pattern = match regex {
+        true => pattern.to_string(),
+        false => regex::escape(pattern),
+    };
+
+    match RegexBuilder::new(&pattern).case_insensitive(ignore_case).build() {
+        Ok(regex) => Ok(regex),
+        Err(e) => Err(KilnError::new(KilnErrorKind::Parse, e.to_string())),
+    }
+}
+
+// A line of a kiln file with every match picked out, quoted just like list
+// would quote it
+fn highlight(id: TagId, val: &str, regex: &Regex) -> String {
+    let quoted = needs_quotes(val);
+    let escape = |text: &str| match quoted {
+        true => escape_value(text),
+        false => text.to_string(),
+    };
+
+    let mut line = format!("{:?} = ", id);
+    if quoted {
+        line.push('"');
+    }
+
+    let mut last = 0;
+    for found in regex.find_iter(val) {
+        line.push_str(&escape(&val[last..found.start()]));
+        line.push_str(&escape(found.as_str()).red().bold().to_string());
+        last = found.end();
+    }
+    line.push_str(&escape(&val[last..]));
+
+    if quoted {
+        line.push('"');
+    }
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use kiln::{
+        parse::parse_input_file,
+        types::id3::TagPair,
+    };
+
+    use super::*;
+
+    fn tag_set(tags: &[(&str, &str)]) -> TagSet {
+        tags.iter().map(|(id, val)| TagPair::from_str(id, val).unwrap()).collect()
+    }
+
+    // Setting the output must leave a file as it was, so the frames that
+    // didn't match are printed along with the ones that did
+    #[test]
+    fn matching_files_keep_every_tag() {
+        colored::control::set_override(false);
+        let tags = tag_set(&[("TALB", "Opal Drifters"), ("TIT2", "Opal"), ("TPE1", "Mountains"), ("COMM", "note \"x\"")]);
+        let regex = pattern_regex("opal", false, true).unwrap();
+
+        let lines = file_lines(&tags, &[TagId::TIT2], &regex).unwrap();
+        let content = format!("[a.mp3]\n{}\n", lines.join("\n"));
+        let document = parse_input_file("search", &content, false, None).unwrap();
+        assert_eq!(document.sections[0].tag_set, tags);
+
+        assert!(file_lines(&tags, &[TagId::TPE1], &regex).is_none());
+    }
+}
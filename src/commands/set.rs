@@ -1,59 +1,103 @@
-use glob::glob;
-use id3::{
-    Error,
-    ErrorKind,
-    Frame,
-    Tag,
-    TagLike,
-    Version,
-};
 use std::{
-    collections::{
-        HashMap,
-        HashSet,
-    },
-    fs,
+    fs::File,
     io::{
         stdin,
         stdout,
+        BufRead,
+        BufReader,
         Write,
     },
 };
 
-use crate::{
-    parse::parse_input_file,
+use kiln::{
+    files::{
+        path_errors,
+        TagCache,
+    },
+    input::read_input,
+    order::sort_diff,
+    plan::{
+        calculate_diff,
+        check_plan,
+        make_plan,
+        planned_diff,
+        write_file_diffs,
+    },
+    query::{
+        filter_diff,
+        parse_query,
+    },
     types::{
-        args::SetArgs,
-        id3::{
-            TagId,
-            TagPair,
-            TagSet,
-        },
+        id3::TagId,
         kiln::{
-            Diff,
             FileDiff,
             KilnError,
             KilnErrorKind,
             KilnResult,
             Section,
         },
+        options::Id3Version,
+        plan::Plan,
+    },
+};
+
+use crate::{
+    args::{
+        InputArgs,
+        SetArgs,
+    },
+    commands::index::{
+        open_index,
+        save_index,
     },
 };
 
 pub fn set_tags(args: SetArgs) -> KilnResult<()> {
-    let content = match fs::read_to_string(args.input_file) {
-        Ok(content) => content,
-        Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
-    };
-    let content = remove_comments(content);
-    let sections = match parse_input_file(&content) {
-        Ok(sections) => sections,
-        Err(e) => return Err(e),
-    };
+    set_jobs(args.jobs)?;
+    let query = args.input.filter.as_deref().map(parse_query).transpose()?;
+    let (sections, preserved_tags) = read_input_args(&args.input)?;
+
+    let mut cache = TagCache::new().with_index(open_index(args.no_index));
+    let mut diff = calculate_diff(sections, preserved_tags, &mut cache)?;
+    if let Some(query) = &query {
+        diff = filter_diff(diff, query, &mut cache)?;
+    }
+    sort_diff(&mut diff, args.order.into(), &mut cache)?;
+
+    let from_stdin = args.input.input_file.as_os_str() == "-";
+    apply_diff(diff, args.ask, from_stdin, args.id3_version.into(), &mut cache)?;
+    save_index(&mut cache);
+    Ok(())
+}
+
+pub fn apply_diff(
+    diff: Vec<FileDiff>,
+    ask: bool,
+    from_stdin: bool,
+    id3_version: Id3Version,
+    cache: &mut TagCache,
+) -> KilnResult<()> {
+    // Plan the changes before showing them, so that if a file changes while
+    // we wait for an answer, we refuse to write to it. Only then are the
+    // files hashed, since without asking there's no time for them to change
+    let plan = make_plan(&diff, ask, cache)?;
+
+    if !confirm_changes(&diff, ask, from_stdin)? {
+        return Ok(());
+    }
 
-    let diff = calculate_diff(sections, args.preserved_tags)?;
+    // Without asking there's no time for anything to change, so there's no
+    // need to read every file again to check
+    if ask {
+        check_plan(&plan, cache)?;
+    }
+    commit_changes_to_files(&plan, id3_version, cache)
+}
+
+// Show the changes about to be made, and ask about them if need be
+pub fn confirm_changes(diff: &[FileDiff], ask: bool, from_stdin: bool) -> KilnResult<bool> {
     let mut no_diffs = true;
-    for filediff in &diff {
+    for filediff in diff {
         if !filediff.diffs.is_empty() {
             no_diffs = false;
             println!("{}\n", filediff);
@@ -62,105 +106,36 @@ pub fn set_tags(args: SetArgs) -> KilnResult<()> {
 
     if no_diffs {
         println!("No changes to make to any files, exiting...");
-        return Ok(());
+        return Ok(false);
     }
 
-    if args.ask {
-        match &get_user_confirmation()[..] {
+    if ask {
+        match &get_user_confirmation("Allow the above changes to be written to files?", from_stdin)?[..] {
             "y" | "yes" | "" => {},
             _ => {
                 println!("No changes will be made to files. Exiting...");
-                return Ok(());
+                return Ok(false);
             }
             // Anything that isn't "Yes" is "No"
         }
     }
 
-    println!("Making changes to files...");
-    commit_changes_to_files(diff)?;
-
-    Ok(())
-}
-
-fn remove_comments(content: String) -> String {
-    let mut ret = Vec::new();
-    let lines = content.lines().collect::<Vec<_>>();
-
-    for line in lines {
-        // We also remove empty lines
-        if line.len() == 0 { continue; }
-        if &line[0..1] != "#" {
-            ret.push(line);
-        }
-    }
-
-    ret.join("\n")
+    Ok(true)
 }
 
-fn calculate_diff(sections: Vec<Section>, preserved_tags: Vec<TagId>) -> KilnResult<Vec<FileDiff>> {
-    let old_tags = get_old_tags_from_sections(&sections)?;
-    let new_tags = get_new_tags_from_sections(&sections)?;
-
-    let mut diffs = Vec::new();
-
-    for (header, new_set) in new_tags {
-        let mut filediff = FileDiff::from(header.clone());
-
-        // Convert our Sets into Vecs for ease of use
-        let new_set = new_set.iter().collect::<Vec<_>>();
-        let old_set = old_tags.get(&header).unwrap();
-        let old_set = old_set.iter().collect::<Vec<_>>();
-
-        // First we check for new and modified tags
-        for new_tag in &new_set {
-            if let Some(old_tag) = find_tag_by_id(&old_set, new_tag.id) {
-                if old_tag.val != new_tag.val {
-                    filediff.diffs.push(
-                        Diff::Modify(
-                            TagPair::from_id(old_tag.id, old_tag.val),
-                            TagPair::from_id(new_tag.id, new_tag.val.clone())
-                        )
-                    );
-                }
-            } else {
-                filediff.diffs.push(
-                    Diff::Add(
-                        TagPair::from_id(new_tag.id, new_tag.val.clone())
-                    )
-                );
-            }
-        }
-
-        // Then we double back to look for deleted tags
-        for old_tag in old_set {
-            if let Some(_) = find_tag_by_id(&new_set, old_tag.id) {
-                continue;
-            } else {
-                // If we want to preserve this tag, then don't even create the diff
-                if preserved_tags.contains(&old_tag.id) {
-                    continue;
-                }
-                filediff.diffs.push(
-                    Diff::Delete(
-                        TagPair::from_id(old_tag.id, old_tag.val.clone())
-                    )
-                );
-            }
-        }
-
-        diffs.push(filediff);
-    }
-
-    Ok(diffs)
-}
-
-fn get_user_confirmation() -> String {
+pub fn get_user_confirmation(prompt: &str, from_tty: bool) -> KilnResult<String> {
     let mut buf = String::new();
     
-    print!("Allow the above changes to be written to files? [Y/n] ");
+    print!("{} [Y/n] ", prompt);
 
     let _ = stdout().flush();
-    stdin().read_line(&mut buf).expect("Did not enter a valid string");
+
+    // If stdin is already taken up by the input file, ask the terminal instead
+    if from_tty {
+        BufReader::new(File::open("/dev/tty")?).read_line(&mut buf)?;
+    } else {
+        stdin().read_line(&mut buf)?;
+    }
 
     // Remove line breaks
     if let Some('\n') = buf.chars().next_back() {
@@ -170,140 +145,53 @@ fn get_user_confirmation() -> String {
         buf.pop();
     }
 
-    buf.to_lowercase()
+    Ok(buf.to_lowercase())
 }
 
-fn commit_changes_to_files(diff: Vec<FileDiff>) -> KilnResult<()> {
-    for file in diff {
-        write_changes_to_file(file)?;
+// Read the input file the options point at, printing any warnings, and
+// returning its sections along with every tag that should be preserved
+pub fn read_input_args(input: &InputArgs) -> KilnResult<(Vec<Section>, Vec<TagId>)> {
+    let document = read_input(&input.input_file, input.format.map(Into::into), input.lenient, input.delete_empty)?;
+    for warning in &document.warnings {
+        eprintln!("{}\n", warning);
     }
 
-    Ok(())
-}
-
-fn get_old_tags_from_sections(sections: &Vec<Section>) -> KilnResult<HashMap<String, TagSet>> {
-    let mut tag_map = HashMap::new();
-
-    for section in sections {
-        let entries = match glob(&section.header) {
-            Ok(entries) => entries,
-            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
-        };
-        for entry in entries {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
-            };
-
-            if let Some(ext) = entry.extension() {
-                // For now we only want to operate on mp3 files.
-                if ext != "mp3" { continue; }
-
-                let path_string = entry.clone().into_os_string().into_string().unwrap();
-
-                // If we've already added this to the map, ignore it
-                if let Some(_) = tag_map.get(&path_string) { continue; }
-
-                let tag = match Tag::read_from_path(entry) {
-                    Ok(tag) => tag,
-                    Err(Error { kind: ErrorKind::NoTag, .. }) => Tag::new(),
-                    Err(e) => return Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
-                };
-
-                let mut tag_set = HashSet::new();
-                for frame in tag.frames() {
-                    let tag_pair = match TagPair::from_str_with_content(frame.id(), frame.content().clone()) {
-                        Ok(tag_pair) => tag_pair,
-                        Err(e) => return Err(e),
-                    };
-                    tag_set.insert(tag_pair);
-                }
-
-                tag_map.insert(path_string, tag_set);
-            }
-        }
-    }
+    let mut preserved_tags = input.preserved_tags.clone();
+    preserved_tags.extend(document.preserved_tags);
 
-    Ok(tag_map)
+    Ok((document.sections, preserved_tags))
 }
 
-fn get_new_tags_from_sections(sections: &Vec<Section>) -> KilnResult<HashMap<String, TagSet>> {
-    let mut tag_map: HashMap<String, TagSet> = HashMap::new();
-
-    for section in sections {
-        let entries = match glob(&section.header) {
-            Ok(entries) => entries,
-            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
-        };
-        for entry in entries {
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
-            };
-
-            if let Some(ext) = entry.extension() {
-                // For now we only want to operate on mp3 files.
-                if ext != "mp3" { continue; }
-
-                let path_string = entry.clone().into_os_string().into_string().unwrap();
-
-                // If we've already added this, then append to the tag_set
-                let mut new_tag_set = section.tag_set.clone();
-                if let Some(tag_set) = tag_map.get(&path_string) {
-                    new_tag_set.extend(tag_set.clone());
-                }
-                tag_map.insert(path_string, new_tag_set);
-            }
-        }
-    }
-
-    Ok(tag_map)
-}
+// Set how many files are read or written at once, if asked to
+pub fn set_jobs(jobs: Option<usize>) -> KilnResult<()> {
+    let jobs = match jobs {
+        Some(jobs) => jobs,
+        None => return Ok(()),
+    };
 
-fn find_tag_by_id(vec: &Vec<&TagPair>, id: TagId) -> Option<TagPair> {
-    for tag in vec {
-        if tag.id == id {
-            return Some((*tag).clone());
-        }
+    match rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::File, e.to_string())),
     }
-
-    None
 }
 
-fn write_changes_to_file(filediff: FileDiff) -> KilnResult<()> {
-    let mut tag = match Tag::read_from_path(&filediff.filepath) {
-        Ok(tag) => tag,
-        Err(Error { kind: ErrorKind::NoTag, .. }) => Tag::new(),
-        Err(e) => return Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
-    };
+pub fn commit_changes_to_files(plan: &Plan, id3_version: Id3Version, cache: &mut TagCache) -> KilnResult<()> {
+    let diff = plan.files.iter()
+        .map(planned_diff)
+        .collect::<KilnResult<Vec<_>>>()?;
 
-    for change in filediff.diffs {
-        match change {
-            Diff::Add(tag_pair) => {
-                tag.add_frame(
-                    Frame::with_content(
-                        format!("{:?}", tag_pair.id),
-                        tag_pair.val
-                    )
-                );
-            },
-            Diff::Delete(tag_pair) => {
-                tag.remove(format!("{:?}", tag_pair.id));
-            },
-            Diff::Modify(_, tag_pair) => {
-                tag.add_frame(
-                    Frame::with_content(
-                        format!("{:?}", tag_pair.id),
-                        tag_pair.val
-                    )
-                );
-            }
+    println!("Making changes to files...");
+    let results = write_file_diffs(diff, id3_version, cache);
+
+    // Files are written in whatever order they finish, but reported in the
+    // order of the plan, and one failing doesn't stop the rest
+    let mut errors = Vec::new();
+    for (file, res) in plan.files.iter().zip(results) {
+        match res {
+            Ok(_) => println!("Wrote changes to file {:?}", file.path),
+            Err(e) => errors.push((file.path.clone(), e)),
         }
     }
 
-    println!("Writing changes to file {:?} ...", filediff.filepath);
-    match tag.write_to_path(filediff.filepath, Version::Id3v24) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
-    }
+    path_errors(errors, "written")
 }
@@ -0,0 +1,570 @@
+use glob::{
+    glob,
+    Pattern,
+};
+use id3::{
+    Error,
+    ErrorKind,
+    Tag,
+};
+use rayon::prelude::*;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    fs,
+    io::Cursor,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    index::{
+        file_stamp,
+        index_frames,
+        indexed_tag,
+        TagIndex,
+    },
+    order::natural_cmp,
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+            TagSet,
+        },
+        index::IndexedFile,
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            Section,
+        },
+    },
+};
+
+/// Expand a leading '~' in a glob string to the home directory. Anything else,
+/// including '~user' and globs with no home directory to expand to, is left as
+/// it is.
+pub fn handle_glob_string(glob_string: &str) -> String {
+    match (glob_string.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", home, rest),
+        _ => glob_string.to_string(),
+    }
+}
+
+/// Find every mp3 file matched by a glob string.
+pub fn get_filepaths_from_glob(glob_string: &str) -> KilnResult<Vec<PathBuf>> {
+    let mut filepaths = Vec::new();
+
+    let entries = match glob(glob_string) {
+        Ok(entries) => entries,
+        Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+        };
+
+        if let Some(ext) = entry.extension() {
+            // For now we only want to operate on mp3 files.
+            if ext != "mp3" { continue; }
+
+            filepaths.push(entry);
+        }
+    }
+
+    Ok(filepaths)
+}
+
+/// Find every mp3 file matched by a glob string, along with every mp3 file
+/// anywhere beneath the directories it matches.
+pub fn get_filepaths_recursively(glob_string: &str) -> KilnResult<Vec<PathBuf>> {
+    let entries = match glob(glob_string) {
+        Ok(entries) => entries,
+        Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+    };
+
+    let mut filepaths = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+        };
+
+        if entry.is_dir() {
+            let dir_glob = Path::new(&Pattern::escape(&entry.to_string_lossy())).join("**").join("*.mp3");
+            filepaths.extend(get_filepaths_from_glob(&dir_glob.to_string_lossy())?);
+        } else if entry.extension().is_some_and(|ext| ext == "mp3") {
+            filepaths.push(entry);
+        }
+    }
+
+    Ok(filepaths)
+}
+
+/// Read the full tag of a file, including any frames kiln doesn't know about.
+pub fn read_tag(filepath: &Path) -> KilnResult<Tag> {
+    parse_tag(Tag::read_from_path(filepath))
+}
+
+/// Read the tags kiln knows about from a file, which is empty if it has none.
+pub fn read_tag_set(filepath: &Path) -> KilnResult<TagSet> {
+    tag_to_tag_set(&read_tag(filepath)?)
+}
+
+fn parse_tag(res: id3::Result<Tag>) -> KilnResult<Tag> {
+    match res {
+        Ok(tag) => Ok(tag),
+        Err(Error { kind: ErrorKind::NoTag, .. }) => Ok(Tag::new()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
+    }
+}
+
+// Frames kiln doesn't know about, like TSSE or PRIV, are left out here, and
+// since files are always read in full before they're written, they're left
+// alone in the file too
+fn tag_to_tag_set(tag: &Tag) -> KilnResult<TagSet> {
+    let mut tag_set = HashSet::new();
+    for frame in tag.frames() {
+        if let Ok(tag_pair) = TagPair::from_str_with_content(frame.id(), frame.content().clone()) {
+            tag_set.insert(tag_pair);
+        }
+    }
+
+    Ok(tag_set)
+}
+
+/// The tags of every file read so far, so that each file is only read once no
+/// matter how many times its tags are needed. Files are keyed by their
+/// canonical path, so any path to the same file will do. With an index, files
+/// that haven't changed since they were indexed aren't read at all.
+#[derive(Default)]
+pub struct TagCache {
+    index: Option<TagIndex>,
+    files: HashMap<PathBuf, CachedFile>,
+    // How many files have been read from disk, and how many of those were
+    // hashed, so tests can tell nothing is read more than it needs to be
+    #[cfg(test)]
+    reads: usize,
+    #[cfg(test)]
+    hashes: usize,
+}
+
+struct CachedFile {
+    tag: Tag,
+    sha256: Option<String>,
+    // Tags from the index only have the frames kiln knows about, so they
+    // can't be written back to the file as they are
+    whole: bool,
+}
+
+impl TagCache {
+    /// A cache that only reads the tag at the start of each file. Only files
+    /// whose hash is asked for are read in full.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use an index to skip reading files that haven't changed, and keep it up
+    /// to date with the files that are read. Nothing is used if it's `None`.
+    pub fn with_index(self, index: Option<TagIndex>) -> Self {
+        Self { index, ..self }
+    }
+
+    /// The full tag of a file, including any frames kiln doesn't know about.
+    pub fn tag(&mut self, filepath: &Path) -> KilnResult<&Tag> {
+        let path = fs::canonicalize(filepath)?;
+        if !self.files.contains_key(&path) {
+            self.read_missing(std::slice::from_ref(&path), false, true)?;
+        }
+
+        Ok(&self.files[&path].tag)
+    }
+
+    /// The ids of the frames kiln knows about in a file, in the order the
+    /// file has them.
+    pub fn frame_order(&mut self, filepath: &Path) -> KilnResult<Vec<TagId>> {
+        let ids = self.tag(filepath)?
+            .frames()
+            .filter_map(|e| e.id().parse::<TagId>().ok())
+            .collect();
+
+        Ok(ids)
+    }
+
+    /// The tags kiln knows about from a file, which is empty if it has none.
+    pub fn tag_set(&mut self, filepath: &Path) -> KilnResult<TagSet> {
+        tag_to_tag_set(self.tag(filepath)?)
+    }
+
+    /// The hash of the whole content of a file, to tell whether it has changed
+    /// since. Its tag is read again along with it, so the two always agree.
+    pub fn hash(&mut self, filepath: &Path) -> KilnResult<String> {
+        let path = fs::canonicalize(filepath)?;
+        if let Some(CachedFile { sha256: Some(sha256), .. }) = self.files.get(&path) {
+            return Ok(sha256.clone());
+        }
+
+        self.files.remove(&path);
+        self.read_missing(std::slice::from_ref(&path), true, true)?;
+
+        Ok(self.files[&path].sha256.clone().unwrap_or_default())
+    }
+
+    /// Read every file that isn't in the cache yet, spread across rayon's
+    /// thread pool. If any can't be read, the error names every one of them.
+    pub fn read_all(&mut self, filepaths: &[PathBuf]) -> KilnResult<()> {
+        self.read_missing(filepaths, false, true)
+    }
+
+    /// Read every file again along with its hash, like `read_all`, whether or
+    /// not it was already in the cache or the index.
+    pub fn refresh_all(&mut self, filepaths: &[PathBuf]) -> KilnResult<()> {
+        for filepath in filepaths {
+            self.forget(filepath)?;
+        }
+        self.read_missing(filepaths, true, false)
+    }
+
+    /// Take a file's tag out of the cache to make changes to it, reading it
+    /// first if need be. Since the file is about to change, it's dropped from
+    /// the index too.
+    pub fn take(&mut self, filepath: &Path) -> KilnResult<Tag> {
+        let path = fs::canonicalize(filepath)?;
+        if let Some(index) = &mut self.index {
+            index.remove(&path);
+        }
+
+        match self.files.remove(&path) {
+            Some(CachedFile { tag, whole: true, .. }) => Ok(tag),
+            _ => read_tag(&path),
+        }
+    }
+
+    /// Forget what was read from a file, so that it's read again the next time
+    /// it's needed, like once it has been written to.
+    pub fn forget(&mut self, filepath: &Path) -> KilnResult<()> {
+        self.files.remove(&fs::canonicalize(filepath)?);
+        Ok(())
+    }
+
+    /// Anything the index had trouble loading, as described for
+    /// `TagIndex::take_warnings`.
+    pub fn index_warnings(&mut self) -> Vec<String> {
+        match &mut self.index {
+            Some(index) => index.take_warnings(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Write out whatever has changed in the index, if there is one.
+    pub fn save_index(&mut self) -> KilnResult<()> {
+        match &mut self.index {
+            Some(index) => index.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn read_missing(&mut self, filepaths: &[PathBuf], hash: bool, from_index: bool) -> KilnResult<()> {
+        let mut seen = HashSet::new();
+        let mut paths = Vec::new();
+        for filepath in filepaths {
+            let path = fs::canonicalize(filepath)?;
+            if self.files.contains_key(&path) || !seen.insert(path.clone()) { continue; }
+
+            if from_index {
+                if let Some(file) = self.indexed_file(&path, hash)? {
+                    self.files.insert(path, file);
+                    continue;
+                }
+            }
+            paths.push(path);
+        }
+
+        // Files are read in any order, but the results come back in order, so
+        // errors are always reported the same way. Each file is stamped before
+        // it's read, so that if it changes in between, it's read again later.
+        let results = paths.par_iter()
+            .map(|path| {
+                let stamp = file_stamp(&fs::metadata(path)?);
+                Ok((stamp, read_file(path, hash)?))
+            })
+            .collect::<Vec<KilnResult<_>>>();
+        #[cfg(test)]
+        {
+            self.reads += paths.len();
+            self.hashes += if hash { paths.len() } else { 0 };
+        }
+
+        let mut errors = Vec::new();
+        for (path, res) in paths.into_iter().zip(results) {
+            match res {
+                Ok((stamp, file)) => {
+                    if let Some(index) = &mut self.index {
+                        let frames = index_frames(&file.tag);
+                        index.insert(&path, IndexedFile { stamp, sha256: file.sha256.clone(), frames });
+                    }
+                    self.files.insert(path, file);
+                },
+                Err(e) => errors.push((path, e)),
+            }
+        }
+
+        path_errors(errors, "read")
+    }
+
+    // Whatever the index has for a file, as long as the file hasn't changed
+    // since, and it has a hash if one is needed
+    fn indexed_file(&mut self, path: &Path, hash: bool) -> KilnResult<Option<CachedFile>> {
+        let index = match &mut self.index {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let stamp = file_stamp(&fs::metadata(path)?);
+
+        match index.get(path) {
+            Some(indexed) if indexed.stamp == stamp && (!hash || indexed.sha256.is_some()) => {
+                Ok(Some(CachedFile {
+                    tag: indexed_tag(&indexed.frames)?,
+                    sha256: indexed.sha256.clone(),
+                    whole: false,
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+// Read just the tag at the start of a file, or the whole file if it's to be
+// hashed too
+fn read_file(path: &Path, hash: bool) -> KilnResult<CachedFile> {
+    if !hash {
+        return Ok(CachedFile { tag: read_tag(path)?, sha256: None, whole: true });
+    }
+
+    let content = fs::read(path)?;
+    Ok(CachedFile {
+        tag: parse_tag(Tag::read_from2(Cursor::new(&content)))?,
+        sha256: Some(format!("{:x}", Sha256::digest(&content))),
+        whole: true,
+    })
+}
+
+/// Gather up the errors for each of many files into one that names them all,
+/// where `action` is what couldn't be done to them, like "read".
+pub fn path_errors(mut errors: Vec<(PathBuf, KilnError)>, action: &str) -> KilnResult<()> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => {
+            let (path, e) = errors.remove(0);
+            Err(KilnError::new(e.kind, format!("{}: {}", path.display(), e.message)))
+        },
+        count => {
+            let messages = errors.iter()
+                .map(|(path, e)| format!("{}: {}", path.display(), e.message))
+                .collect::<Vec<_>>();
+            Err(KilnError::new(
+                errors[0].1.kind,
+                format!("{} files couldn't be {}:\n{}", count, action, messages.join("\n"))
+            ))
+        },
+    }
+}
+
+/// Read the tags that every one of the given files has in common.
+pub fn construct_shared_tags(filepaths: &[PathBuf], cache: &mut TagCache) -> KilnResult<TagSet> {
+    if filepaths.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let mut tag_sets = Vec::new();
+    for filepath in filepaths {
+        tag_sets.push(cache.tag_set(filepath)?);
+    }
+
+    let (intersection, others) = tag_sets.split_at_mut(1);
+    let intersection = &mut intersection[0];
+    for other in others {
+        intersection.retain(|e| other.contains(e));
+    }
+
+    Ok(intersection.clone())
+}
+
+/// List the files matched by a glob as sections, just like `kiln list` does:
+/// one for the glob with the tags every file shares, if there are any, then
+/// one for each file with the rest of its tags.
+pub fn list_sections(glob_string: &str) -> KilnResult<Vec<Section>> {
+    let mut cache = TagCache::new();
+    let mut filepaths = get_filepaths_from_glob(glob_string)?;
+    filepaths.sort_by(|a, b| natural_cmp(a, b));
+    cache.read_all(&filepaths)?;
+    let shared_tags = construct_shared_tags(&filepaths, &mut cache)?;
+
+    let mut sections = Vec::new();
+    if !shared_tags.is_empty() {
+        sections.push(Section { header: glob_string.to_string(), tag_set: shared_tags.clone() });
+    }
+
+    for filepath in &filepaths {
+        let tag_set = cache.tag_set(filepath)?
+            .difference(&shared_tags)
+            .cloned()
+            .collect::<TagSet>();
+        if tag_set.is_empty() { continue; }
+
+        // Escape the path so that it isn't treated as a glob
+        let header = Pattern::escape(&filepath.to_string_lossy());
+        sections.push(Section { header, tag_set });
+    }
+
+    Ok(sections)
+}
+
+/// The directory a path is in, which is '.' for a bare file name.
+pub fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Write to a temporary file alongside the destination and then move it into
+/// place, so that readers never see a half-written file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> KilnResult<()> {
+    let filename = match path.file_name() {
+        Some(filename) => filename.to_string_lossy(),
+        None => return Err(KilnError::new(KilnErrorKind::File, format!("{} is not a file", path.display()))),
+    };
+    let tmp_path = parent_dir(path).join(format!(".{}.{}.tmp", filename, std::process::id()));
+
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use super::*;
+    use crate::plan::{
+        calculate_diff,
+        make_plan,
+    };
+
+    // A directory of its own for each test, so tests can run side by side
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kiln-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_a_leading_tilde_is_expanded() {
+        assert_eq!(handle_glob_string(""), "");
+        assert_eq!(handle_glob_string("é/*.mp3"), "é/*.mp3");
+        assert_eq!(handle_glob_string("~user/*.mp3"), "~user/*.mp3");
+        assert_eq!(handle_glob_string("./~/*.mp3"), "./~/*.mp3");
+        if let Ok(home) = std::env::var("HOME") {
+            assert_eq!(handle_glob_string("~"), home);
+            assert_eq!(handle_glob_string("~/Music/*"), format!("{}/Music/*", home));
+        }
+    }
+
+    #[test]
+    fn escaped_paths_only_match_their_own_file() {
+        let dir = test_dir("escaped-paths");
+        let names = ["track [1].mp3", "track 1.mp3", "what?.mp3", "whatt.mp3", "*.mp3", "[ab].mp3", "a.mp3"];
+        for name in names {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        for name in names {
+            let glob_string = Pattern::escape(&dir.join(name).to_string_lossy());
+            let filepaths = get_filepaths_from_glob(&glob_string).unwrap();
+            assert_eq!(filepaths, vec![dir.join(name)], "{}", glob_string);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Albums of twelve tracks, each with a tag and some stand-in audio after it
+    fn synthetic_library(dir: &Path, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("{:05} Track.mp3", i));
+                fs::write(&path, vec![0u8; 16 * 1024]).unwrap();
+
+                let mut tag = Tag::new();
+                tag.set_artist("Synthetic Artist");
+                tag.set_album(format!("Album {}", i / 12));
+                tag.set_title(format!("Track {}", i));
+                tag.set_track((i % 12 + 1) as u32);
+                tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn files_are_read_once_and_only_hashed_for_plans() {
+        let dir = test_dir("read-counts");
+        let filepaths = synthetic_library(&dir, 24);
+
+        // Listing reads every file for the shared tags and again for the
+        // output, but only the first of those goes to disk
+        let mut cache = TagCache::new();
+        cache.read_all(&filepaths).unwrap();
+        construct_shared_tags(&filepaths, &mut cache).unwrap();
+        for filepath in &filepaths {
+            cache.tag_set(filepath).unwrap();
+        }
+        assert_eq!((cache.reads, cache.hashes), (24, 0));
+
+        // A kiln file for the whole library that gives the first track of
+        // every album a new title
+        let sections = || filepaths.iter()
+            .enumerate()
+            .map(|(i, filepath)| {
+                let mut tag_set = read_tag_set(filepath).unwrap();
+                if i % 12 == 0 {
+                    tag_set.retain(|e| e.id != TagId::TIT2);
+                    tag_set.insert(TagPair::from_str("TIT2", "Renamed").unwrap());
+                }
+                Section { header: Pattern::escape(&filepath.to_string_lossy()), tag_set }
+            })
+            .collect::<Vec<_>>();
+
+        // Setting without asking has no use for hashes
+        let mut cache = TagCache::new();
+        let diff = calculate_diff(sections(), Vec::new(), &mut cache).unwrap();
+        let plan = make_plan(&diff, false, &mut cache).unwrap();
+        assert_eq!(plan.files.len(), 2);
+        assert!(plan.files.iter().all(|e| e.sha256.is_none()));
+        assert_eq!((cache.reads, cache.hashes), (24, 0));
+
+        // A plan to check later only reads again the files that change
+        let mut cache = TagCache::new();
+        let diff = calculate_diff(sections(), Vec::new(), &mut cache).unwrap();
+        let plan = make_plan(&diff, true, &mut cache).unwrap();
+        assert!(plan.files.iter().all(|e| e.sha256.is_some()));
+        assert_eq!((cache.reads, cache.hashes), (26, 2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
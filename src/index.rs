@@ -0,0 +1,421 @@
+use id3::{
+    Frame,
+    Tag,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::{
+    collections::HashMap,
+    fs::{
+        self,
+        Metadata,
+    },
+    io::ErrorKind,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    files::{
+        read_tag,
+        write_atomically,
+    },
+    json::{
+        json_to_tag,
+        tag_to_exact_json,
+    },
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+        },
+        index::{
+            DirIndex,
+            FileStamp,
+            IndexedFile,
+            INDEX_VERSION,
+        },
+        json::JsonValue,
+        kiln::{
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+        },
+    },
+};
+
+/// What `TagIndex::check` found out about a file.
+#[derive(Debug, Eq, PartialEq)]
+pub enum IndexCheck {
+    /// The file isn't in the index at all
+    Missing,
+    /// The file has changed since it was indexed, so it'll be read again
+    OutOfDate,
+    /// The index agrees with the file
+    UpToDate,
+    /// The file looks unchanged, but the index doesn't agree with it
+    Wrong,
+}
+
+/// Tags kept on disk between runs, so that files which haven't changed don't
+/// need to be read again. Each directory of music gets an index file of its
+/// own, which is only loaded when a file in that directory is looked up.
+pub struct TagIndex {
+    root: PathBuf,
+    dirs: HashMap<PathBuf, LoadedDir>,
+    warnings: Vec<String>,
+}
+
+struct LoadedDir {
+    index: DirIndex,
+    changed: bool,
+}
+
+impl TagIndex {
+    /// The index kept in the given directory.
+    pub fn open(root: PathBuf) -> Self {
+        Self { root, dirs: HashMap::new(), warnings: Vec::new() }
+    }
+
+    /// The index kept in the user's cache directory, which is
+    /// '$XDG_CACHE_HOME/kiln/index', or '~/.cache/kiln/index' if that isn't set.
+    pub fn open_default() -> Option<Self> {
+        let cache_dir = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+            (Some(cache_dir), _) if !cache_dir.is_empty() => PathBuf::from(cache_dir),
+            (_, Some(home)) => PathBuf::from(home).join(".cache"),
+            (_, None) => return None,
+        };
+
+        Some(Self::open(cache_dir.join("kiln").join("index")))
+    }
+
+    /// The directory the index is kept in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// What the index holds for a file, given its canonical path, whether or
+    /// not the file has changed since.
+    pub fn get(&mut self, path: &Path) -> Option<&IndexedFile> {
+        let (dir, name) = split_path(path)?;
+        self.load(&dir).index.files.get(&name)
+    }
+
+    /// Store what was read from a file, given its canonical path.
+    pub fn insert(&mut self, path: &Path, file: IndexedFile) {
+        if let Some((dir, name)) = split_path(path) {
+            let loaded = self.load(&dir);
+            loaded.index.files.insert(name, file);
+            loaded.changed = true;
+        }
+    }
+
+    /// Drop a file from the index, like when it's about to be written to.
+    pub fn remove(&mut self, path: &Path) {
+        if let Some((dir, name)) = split_path(path) {
+            let loaded = self.load(&dir);
+            if loaded.index.files.remove(&name).is_some() {
+                loaded.changed = true;
+            }
+        }
+    }
+
+    /// The files the index holds for a directory that no longer exist.
+    pub fn missing_files(&mut self, dir: &Path) -> Vec<PathBuf> {
+        self.load(dir).index.files.keys()
+            .map(|name| dir.join(name))
+            .filter(|path| !path.exists())
+            .collect()
+    }
+
+    /// Drop every file in a directory that no longer exists, giving back the
+    /// paths of the files that were dropped.
+    pub fn prune(&mut self, dir: &Path) -> Vec<PathBuf> {
+        let gone = self.missing_files(dir);
+        for path in &gone {
+            self.remove(path);
+        }
+
+        gone
+    }
+
+    /// Compare what the index holds for a file with the file itself, reading
+    /// the file again if the index thinks it's unchanged.
+    pub fn check(&mut self, path: &Path) -> KilnResult<IndexCheck> {
+        let stamp = file_stamp(&fs::metadata(path)?);
+        let indexed = match self.get(path) {
+            Some(indexed) if indexed.stamp != stamp => return Ok(IndexCheck::OutOfDate),
+            Some(indexed) => indexed.clone(),
+            None => return Ok(IndexCheck::Missing),
+        };
+
+        if index_frames(&read_tag(path)?) != indexed.frames {
+            return Ok(IndexCheck::Wrong);
+        }
+        if let Some(sha256) = indexed.sha256 {
+            if sha256 != format!("{:x}", Sha256::digest(fs::read(path)?)) {
+                return Ok(IndexCheck::Wrong);
+            }
+        }
+
+        Ok(IndexCheck::UpToDate)
+    }
+
+    /// Index files that couldn't be loaded since they were last asked for.
+    /// They're ignored, so they only cost the time it takes to read their
+    /// files again, and are replaced when the index is next saved.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Write out the index of every directory that has changed.
+    pub fn save(&mut self) -> KilnResult<()> {
+        for loaded in self.dirs.values_mut() {
+            if !loaded.changed { continue; }
+
+            let path = index_path(&self.root, &loaded.index.dir);
+            if loaded.index.files.is_empty() {
+                if path.exists() {
+                    fs::remove_file(&path)?;
+                }
+            } else {
+                let buf = match serde_json::to_vec(&loaded.index) {
+                    Ok(buf) => buf,
+                    Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+                };
+                fs::create_dir_all(&self.root)?;
+                write_atomically(&path, &buf)?;
+            }
+
+            loaded.changed = false;
+        }
+
+        Ok(())
+    }
+
+    // An index that can't be read is as good as none, since everything in it
+    // can just be read again
+    fn load(&mut self, dir: &Path) -> &mut LoadedDir {
+        let root = &self.root;
+        let warnings = &mut self.warnings;
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            let path = index_path(root, dir);
+            let index = match fs::read(&path) {
+                Ok(content) => match serde_json::from_slice::<DirIndex>(&content) {
+                    Ok(index) => Some(index),
+                    Err(e) => {
+                        warnings.push(format!("{} couldn't be read, so it's being ignored: {}", path.display(), e));
+                        None
+                    },
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => {
+                    warnings.push(format!("{} couldn't be read, so it's being ignored: {}", path.display(), e));
+                    None
+                },
+            };
+            let index = index.filter(|index| index.version == INDEX_VERSION && index.dir == dir);
+
+            LoadedDir {
+                index: index.unwrap_or_else(|| DirIndex {
+                    version: INDEX_VERSION,
+                    dir: dir.to_path_buf(),
+                    files: Default::default(),
+                }),
+                changed: false,
+            }
+        })
+    }
+}
+
+/// Enough about a file to tell whether it has changed since.
+pub fn file_stamp(metadata: &Metadata) -> FileStamp {
+    let mtime = metadata.modified().ok()
+        .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+
+    FileStamp {
+        size: metadata.len(),
+        mtime: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        inode,
+    }
+}
+
+/// The frames of a tag as the index stores them. Only the frames kiln knows
+/// about are kept, since they're the only ones it ever lists, and files are
+/// read in full before they're written.
+pub fn index_frames(tag: &Tag) -> Vec<(TagId, JsonValue)> {
+    tag.frames()
+        .filter_map(|frame| {
+            let tag_pair = TagPair::from_str_with_content(frame.id(), frame.content().clone()).ok()?;
+            Some((tag_pair.id, tag_to_exact_json(&tag_pair)))
+        })
+        .collect()
+}
+
+/// Rebuild a tag from the frames the index stored for it.
+pub fn indexed_tag(frames: &[(TagId, JsonValue)]) -> KilnResult<Tag> {
+    let mut tag = Tag::new();
+    for (id, value) in frames {
+        let tag_pair = json_to_tag(*id, value.clone(), None)?;
+        tag.extend([Frame::with_content(format!("{:?}", tag_pair.id), tag_pair.val)]);
+    }
+
+    Ok(tag)
+}
+
+fn split_path(path: &Path) -> Option<(PathBuf, String)> {
+    Some((path.parent()?.to_path_buf(), path.file_name()?.to_string_lossy().to_string()))
+}
+
+// Each directory's index is named after the hash of its path, so any path at
+// all can have one
+fn index_path(root: &Path, dir: &Path) -> PathBuf {
+    let hash = Sha256::digest(dir.to_string_lossy().as_bytes());
+    root.join(format!("{:x}.json", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::{
+        TagLike,
+        Version,
+    };
+    use std::{
+        fs::File,
+        io::Write,
+        time::{
+            Duration,
+            SystemTime,
+        },
+    };
+
+    use super::*;
+    use crate::files::TagCache;
+
+    // Every file gets a frame kiln doesn't know about, which mustn't stop it
+    // being indexed. Titles of the same length keep the file the same size.
+    fn write_title(path: &Path, title: &str) {
+        let mut tag = Tag::new();
+        tag.set_title(title);
+        tag.set_text("TSSE", "LAME 3.100");
+        tag.write_to_path(path, Version::Id3v24).unwrap();
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    fn title(cache: &mut TagCache, path: &Path) -> String {
+        let tag_set = cache.tag_set(path).unwrap();
+        tag_set.iter().find(|e| e.id == TagId::TIT2).unwrap().display_value()
+    }
+
+    fn music_dir(name: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("kiln-index-{}-{}", name, std::process::id()));
+        fs::create_dir_all(root.join("music")).unwrap();
+        let path = root.join("music").join("a.mp3");
+        fs::write(&path, []).unwrap();
+        write_title(&path, "One");
+
+        (fs::canonicalize(&root).unwrap(), fs::canonicalize(&path).unwrap())
+    }
+
+    // The file is changed behind the index's back, keeping its size, mtime
+    // and inode, so only a file taken from the index still says 'One'
+    #[test]
+    fn unchanged_files_come_from_the_index() {
+        let (root, path) = music_dir("hit");
+        let mut cache = TagCache::new().with_index(Some(TagIndex::open(root.join("index"))));
+        assert_eq!(title(&mut cache, &path), "One");
+        cache.save_index().unwrap();
+
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        write_title(&path, "Two");
+        set_mtime(&path, mtime);
+
+        let mut cache = TagCache::new().with_index(Some(TagIndex::open(root.join("index"))));
+        assert_eq!(title(&mut cache, &path), "One");
+        let mut cache = TagCache::new();
+        assert_eq!(title(&mut cache, &path), "Two");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn changed_files_are_read_again() {
+        let (root, path) = music_dir("changed");
+        let mut cache = TagCache::new().with_index(Some(TagIndex::open(root.join("index"))));
+        title(&mut cache, &path);
+        cache.save_index().unwrap();
+
+        // A new modification time is enough on its own
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        write_title(&path, "Two");
+        set_mtime(&path, mtime + Duration::from_secs(60));
+        let mut cache = TagCache::new().with_index(Some(TagIndex::open(root.join("index"))));
+        assert_eq!(title(&mut cache, &path), "Two");
+        cache.save_index().unwrap();
+
+        // And so is a new size, even with the old modification time
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        write_title(&path, "Six");
+        File::options().append(true).open(&path).unwrap().write_all(&[0]).unwrap();
+        set_mtime(&path, mtime);
+        let mut cache = TagCache::new().with_index(Some(TagIndex::open(root.join("index"))));
+        assert_eq!(title(&mut cache, &path), "Six");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn check_compares_the_index_with_the_file() {
+        let (root, path) = music_dir("check");
+        let mut index = TagIndex::open(root.join("index"));
+        assert_eq!(index.check(&path).unwrap(), IndexCheck::Missing);
+
+        let mut cache = TagCache::new().with_index(Some(index));
+        title(&mut cache, &path);
+        cache.save_index().unwrap();
+        assert_eq!(TagIndex::open(root.join("index")).check(&path).unwrap(), IndexCheck::UpToDate);
+
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        write_title(&path, "Two");
+        set_mtime(&path, mtime);
+        assert_eq!(TagIndex::open(root.join("index")).check(&path).unwrap(), IndexCheck::Wrong);
+
+        set_mtime(&path, mtime + Duration::from_secs(60));
+        assert_eq!(TagIndex::open(root.join("index")).check(&path).unwrap(), IndexCheck::OutOfDate);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unreadable_index_files_are_warned_about_and_ignored() {
+        let root = std::env::temp_dir().join(format!("kiln-index-broken-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(index_path(&root, Path::new("/music/broken")), "not json").unwrap();
+
+        let mut index = TagIndex::open(root.clone());
+        assert!(index.get(Path::new("/music/missing/a.mp3")).is_none());
+        assert!(index.take_warnings().is_empty());
+
+        assert!(index.get(Path::new("/music/broken/a.mp3")).is_none());
+        let warnings = index.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("couldn't be read"), "{}", warnings[0]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use glob::Pattern;
+use std::{
+    fs,
+    io::{
+        stdin,
+        Read,
+    },
+    path::Path,
+};
+
+use crate::{
+    json::parse_json_file,
+    parse::parse_input_file,
+    structured::{
+        parse_toml_file,
+        parse_yaml_file,
+    },
+    table::parse_table_file,
+    types::{
+        kiln::{
+            KilnDocument,
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            Section,
+        },
+        options::Format,
+    },
+};
+
+/// Read and parse an input file (or stdin, for '-') in whatever format it's
+/// in, guessing the format if it isn't given. The document's headers come back
+/// resolved, as described for `resolve_headers`.
+pub fn read_input(input_file: &Path, format: Option<Format>, lenient: bool, delete_empty: bool) -> KilnResult<KilnDocument> {
+    let from_stdin = input_file.as_os_str() == "-";
+    let (content, filename) = if from_stdin {
+        let mut content = String::new();
+        stdin().read_to_string(&mut content)?;
+        (content, String::from("<stdin>"))
+    } else {
+        match fs::read_to_string(input_file) {
+            Ok(content) => (content, input_file.to_string_lossy().to_string()),
+            Err(e) => return Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+        }
+    };
+    let format = format.unwrap_or_else(|| input_format(input_file, &content));
+    let dir = if from_stdin { None } else { input_dir(input_file) };
+    let document = parse_document(&filename, &content, format, lenient, delete_empty, dir)?;
+
+    let sections = match document.path_mode.base_dir(dir) {
+        Some(dir) => resolve_headers(document.sections, dir),
+        None => document.sections,
+    };
+
+    Ok(KilnDocument { sections, ..document })
+}
+
+/// Parse the content of an input file in the given format. `lenient` only
+/// applies to kiln files, and `delete_empty` only to CSV and TSV files. `dir`
+/// is the directory the file is in, which relative image paths in APIC frames
+/// are read from, unless the file says its paths are relative to the current
+/// directory.
+pub fn parse_document(
+    filename: &str,
+    content: &str,
+    format: Format,
+    lenient: bool,
+    delete_empty: bool,
+    dir: Option<&Path>,
+) -> KilnResult<KilnDocument> {
+    match format {
+        Format::Kiln => parse_input_file(filename, content, lenient, dir),
+        Format::Json => parse_json_file(filename, content, dir),
+        Format::Csv => parse_table_file(filename, content, b',', delete_empty, dir),
+        Format::Tsv => parse_table_file(filename, content, b'\t', delete_empty, dir),
+        Format::Yaml => parse_yaml_file(filename, content, dir),
+        Format::Toml => parse_toml_file(filename, content, dir),
+    }
+}
+
+/// The directory an input file is in, or `None` if it's in the current one.
+pub fn input_dir(input_file: &Path) -> Option<&Path> {
+    match input_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Some(parent),
+        _ => None,
+    }
+}
+
+/// Guess the format of an input file by its extension, and failing that,
+/// whether the content looks like a JSON object (which a kiln file never can).
+pub fn input_format(input_file: &Path, content: &str) -> Format {
+    match input_file.extension() {
+        Some(ext) if ext == "json" => Format::Json,
+        Some(ext) if ext == "kiln" => Format::Kiln,
+        Some(ext) if ext == "csv" => Format::Csv,
+        Some(ext) if ext == "tsv" => Format::Tsv,
+        Some(ext) if ext == "yaml" || ext == "yml" => Format::Yaml,
+        Some(ext) if ext == "toml" => Format::Toml,
+        _ if content.trim_start().starts_with('{') => Format::Json,
+        _ => Format::Kiln,
+    }
+}
+
+/// Relative headers are taken to be relative to the directory of the input
+/// file, so prefix them with that (escaped) directory before globbing.
+pub fn resolve_headers(sections: Vec<Section>, dir: &Path) -> Vec<Section> {
+    let parent = dir.to_string_lossy();
+
+    sections.into_iter()
+        .map(|section| {
+            if Path::new(&section.header).is_relative() {
+                let header = format!("{}/{}", Pattern::escape(&parent), section.header);
+                Section { header, ..section }
+            } else {
+                section
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::frame::Content;
+
+    use super::*;
+    use crate::types::id3::TagId;
+
+    // The test runs from the crate root, which has no cover.png of its own,
+    // so the image can only be found next to the input file
+    #[test]
+    fn images_are_read_relative_to_the_input_file_like_headers() {
+        let dir = std::env::temp_dir().join(format!("kiln-input-images-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::new(2, 2).save(dir.join("cover.png")).unwrap();
+
+        let kiln_file = dir.join("tags.kiln");
+        fs::write(&kiln_file, "[*.mp3]\nAPIC = Back cover: cover.png\n").unwrap();
+        let document = read_input(&kiln_file, None, false, false).unwrap();
+        let section = &document.sections[0];
+        assert_eq!(section.header, format!("{}/*.mp3", Pattern::escape(&dir.to_string_lossy())));
+        let picture = section.tag_set.iter().find(|e| e.id == TagId::APIC).unwrap();
+        assert!(matches!(&picture.val, Content::Picture(picture) if !picture.data.is_empty()));
+
+        let json_file = dir.join("tags.json");
+        fs::write(&json_file, r#"{"version": 2, "glob": "*.mp3", "shared": {"APIC": "cover.png"}}"#).unwrap();
+        assert!(read_input(&json_file, None, false, false).is_ok());
+
+        // With paths relative to the current directory, images are too
+        fs::write(&kiln_file, "@paths = cwd\n\n[*.mp3]\nAPIC = cover.png\n").unwrap();
+        assert!(read_input(&kiln_file, None, false, false).unwrap_err().message.contains("bad image"));
+        fs::write(&json_file, r#"{"version": 2, "paths": "cwd", "glob": "*.mp3", "shared": {"APIC": "cover.png"}}"#).unwrap();
+        assert!(matches!(read_input(&json_file, None, false, false).unwrap_err().kind, KilnErrorKind::Image));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+use base64::{
+    engine::general_purpose::STANDARD as BASE64,
+    Engine,
+};
+use glob::Pattern;
+use id3::frame::{
+    Comment,
+    Content,
+    ExtendedText,
+    Picture,
+};
+use std::path::Path;
+
+use crate::types::{
+    diagnostic::Diagnostic,
+    id3::{
+        picture_type_from_u8,
+        TagId,
+        TagPair,
+        TagSet,
+    },
+    json::{
+        JsonComment,
+        JsonDocument,
+        JsonExtendedText,
+        JsonPicture,
+        JsonTags,
+        JsonValue,
+        SCHEMA_VERSION,
+    },
+    kiln::{
+        KilnDocument,
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+        Section,
+    },
+};
+
+/// Convert tags to their JSON representation, optionally embedding pictures.
+/// Every COMM, TXXX and APIC frame goes in a list with the others of its id,
+/// sorted by what tells them apart.
+pub fn tags_to_json(tag_set: &TagSet, embed_pictures: bool) -> JsonTags {
+    let mut tags = JsonTags::new();
+
+    for tag in tag_set {
+        let val = match tag_to_json(tag, embed_pictures) {
+            JsonValue::Comment(comment) => JsonValue::Comments(vec![comment]),
+            JsonValue::Picture(picture) => JsonValue::Pictures(vec![picture]),
+            val => val,
+        };
+
+        match (tags.get_mut(&tag.id), val) {
+            (Some(JsonValue::Extended(texts)), JsonValue::Extended(more)) => texts.extend(more),
+            (Some(JsonValue::Comments(comments)), JsonValue::Comments(more)) => comments.extend(more),
+            (Some(JsonValue::Pictures(pictures)), JsonValue::Pictures(more)) => pictures.extend(more),
+            (_, val) => {
+                tags.insert(tag.id, val);
+            },
+        }
+    }
+
+    for val in tags.values_mut() {
+        match val {
+            JsonValue::Extended(texts) => texts.sort_by(|a, b| a.description.cmp(&b.description)),
+            JsonValue::Comments(comments) => comments.sort_by(|a, b| (&a.lang, &a.description).cmp(&(&b.lang, &b.description))),
+            JsonValue::Pictures(pictures) => pictures.sort_by_key(|e| e.picture_type),
+            _ => {},
+        }
+    }
+
+    tags
+}
+
+/// Convert a single tag to its JSON representation. Everything but the
+/// picture data is kept, like the language of a comment.
+pub fn tag_to_json(tag: &TagPair, embed_pictures: bool) -> JsonValue {
+    match &tag.val {
+        Content::Text(_) => JsonValue::List(tag.values()),
+        Content::Picture(picture) => JsonValue::Picture(JsonPicture {
+            mime_type: picture.mime_type.clone(),
+            picture_type: picture.picture_type.into(),
+            description: picture.description.clone(),
+            size: picture.data.len(),
+            data: embed_pictures.then(|| BASE64.encode(&picture.data)),
+        }),
+        Content::Comment(comment) => JsonValue::Comment(JsonComment {
+            lang: comment.lang.clone(),
+            description: comment.description.clone(),
+            text: comment.text.clone(),
+        }),
+        Content::ExtendedText(text) => JsonValue::Extended(vec![JsonExtendedText {
+            description: text.description.clone(),
+            value: text.value.clone(),
+        }]),
+        val => JsonValue::Text(val.to_string()),
+    }
+}
+
+/// Like `tag_to_json`, but keeping the picture data too, so the tag can be
+/// recreated exactly.
+pub fn tag_to_exact_json(tag: &TagPair) -> JsonValue {
+    tag_to_json(tag, true)
+}
+
+/// Parse a JSON document in the schema `kiln list --format json` prints.
+pub fn parse_json_file(filename: &str, content: &str, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    let document: JsonDocument = match serde_json::from_str(content) {
+        Ok(document) => document,
+        Err(e) => {
+            let diagnostic = diagnostic_at(filename, content, e.line(), e.column(), e.to_string());
+            return Err(KilnError::from_diagnostics(filename, vec![diagnostic]));
+        },
+    };
+
+    if document.version > SCHEMA_VERSION {
+        return Err(KilnError::new(
+            KilnErrorKind::Parse,
+            format!("{} uses schema version {}, but kiln only understands up to version {}", filename, document.version, SCHEMA_VERSION)
+        ));
+    }
+
+    let path_mode = document.paths.unwrap_or_default();
+    let dir = path_mode.base_dir(dir);
+    let mut sections = Vec::new();
+    match document.glob {
+        Some(glob) => sections.push(Section { header: glob, tag_set: json_to_tags(document.shared, dir)? }),
+        None if !document.shared.is_empty() => {
+            return Err(KilnError::new(KilnErrorKind::Parse, "shared tags were given without a glob".to_string()));
+        },
+        None => {},
+    }
+
+    // File paths in JSON are literal, so we escape them before they're globbed
+    for file in document.files {
+        sections.push(Section { header: Pattern::escape(&file.path), tag_set: json_to_tags(file.tags, dir)? });
+    }
+
+    Ok(KilnDocument {
+        path_mode,
+        sections,
+        preserved_tags: Vec::new(),
+        warnings: Vec::new(),
+    })
+}
+
+/// Convert JSON tag values back to tags, reading images named by a relative
+/// path from `dir`, as described for `TagPair::from_str_in`.
+pub fn json_to_tags(tags: JsonTags, dir: Option<&Path>) -> KilnResult<TagSet> {
+    let mut tag_set = TagSet::new();
+
+    for (id, val) in tags {
+        let frames = match val {
+            JsonValue::Extended(texts) => texts.into_iter().map(|e| JsonValue::Extended(vec![e])).collect(),
+            JsonValue::Comments(comments) => comments.into_iter().map(JsonValue::Comment).collect(),
+            JsonValue::Pictures(pictures) => pictures.into_iter().map(JsonValue::Picture).collect(),
+            val => vec![val],
+        };
+
+        for val in frames {
+            let tag_pair = json_to_tag(id, val, dir)?;
+            // Only frames a file can have several of come here more than
+            // once, so the same one twice can't be appended to
+            if let Some(existing) = tag_set.iter().find(|e| e.same_frame(&tag_pair)) {
+                existing.clone().append(tag_pair)?;
+            } else {
+                tag_set.insert(tag_pair);
+            }
+        }
+    }
+
+    Ok(tag_set)
+}
+
+/// Convert a single JSON tag value back to a tag. Lists of COMM, TXXX and APIC
+/// frames can only hold one frame here, since each is a tag of its own.
+pub fn json_to_tag(id: TagId, val: JsonValue, dir: Option<&Path>) -> KilnResult<TagPair> {
+    let id_string = format!("{:?}", id);
+    let tag_pair = match (id, val) {
+        (TagId::APIC, JsonValue::Picture(picture)) => picture_from_json(picture)?,
+        (TagId::COMM, JsonValue::Comment(comment)) => {
+            let comment = Comment {
+                lang: comment.lang,
+                description: comment.description,
+                text: comment.text,
+            };
+            TagPair::from_id(TagId::COMM, Content::Comment(comment))
+        },
+        (TagId::TXXX, JsonValue::Extended(mut texts)) if texts.len() == 1 => {
+            let text = texts.remove(0);
+            let text = ExtendedText {
+                description: text.description,
+                value: text.value,
+            };
+            TagPair::from_id(TagId::TXXX, Content::ExtendedText(text))
+        },
+        (TagId::COMM, JsonValue::Comments(mut comments)) if comments.len() == 1 => {
+            json_to_tag(id, JsonValue::Comment(comments.remove(0)), dir)?
+        },
+        (TagId::APIC, JsonValue::Pictures(mut pictures)) if pictures.len() == 1 => {
+            json_to_tag(id, JsonValue::Picture(pictures.remove(0)), dir)?
+        },
+        (TagId::TXXX, JsonValue::Extended(_))
+        | (TagId::COMM, JsonValue::Comments(_))
+        | (TagId::APIC, JsonValue::Pictures(_)) => {
+            return Err(KilnError::new(KilnErrorKind::Parse, format!("{} must be given one frame at a time here", id_string)));
+        },
+        (_, JsonValue::Text(text)) => TagPair::from_str_in(&id_string, &text, dir)?,
+        (TagId::COMM | TagId::APIC, _) => {
+            return Err(KilnError::new(KilnErrorKind::Parse, format!("{} cannot hold that kind of value", id_string)));
+        },
+        (_, JsonValue::List(values)) => {
+            let mut values = values.iter();
+            let mut tag_pair = match values.next() {
+                Some(first) => TagPair::from_str(&id_string, first)?,
+                None => return Err(KilnError::new(KilnErrorKind::Parse, format!("{} must have at least one value", id_string))),
+            };
+            for val in values {
+                tag_pair.append(TagPair::from_str(&id_string, val)?)?;
+            }
+            tag_pair
+        },
+        (_, JsonValue::Picture(_) | JsonValue::Pictures(_)) => {
+            return Err(KilnError::new(KilnErrorKind::Parse, format!("{} cannot hold a picture", id_string)));
+        },
+        (_, JsonValue::Comment(_) | JsonValue::Comments(_)) => {
+            return Err(KilnError::new(KilnErrorKind::Parse, format!("{} cannot hold a comment", id_string)));
+        },
+        (_, JsonValue::Extended(_)) => {
+            return Err(KilnError::new(KilnErrorKind::Parse, format!("{} cannot hold extended text", id_string)));
+        },
+    };
+
+    Ok(tag_pair)
+}
+
+fn picture_from_json(picture: JsonPicture) -> KilnResult<TagPair> {
+    let data = match picture.data {
+        Some(data) => match BASE64.decode(data) {
+            Ok(data) => data,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::Image, e.to_string())),
+        },
+        None => return Err(KilnError::new(
+            KilnErrorKind::Image,
+            "APIC has no picture data; list with '--pictures base64' or give a path to an image instead".to_string()
+        )),
+    };
+
+    let picture = Picture {
+        mime_type: picture.mime_type,
+        picture_type: picture_type_from_u8(picture.picture_type),
+        description: picture.description,
+        data,
+    };
+
+    Ok(TagPair::from_id(TagId::APIC, Content::Picture(picture)))
+}
+
+/// serde_json reports errors by line and column, so find the matching slice of
+/// the content to point a diagnostic at.
+pub fn diagnostic_at(filename: &str, content: &str, line: usize, column: usize, message: String) -> Diagnostic {
+    let line_offset = content.split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>();
+    let line_content = &content[line_offset..];
+    let column_offset = line_content.char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(line_content.len(), |(i, _)| i);
+    let span = &line_content[column_offset..];
+    let span_end = span.char_indices().nth(1).map_or(span.len(), |(i, _)| i);
+
+    Diagnostic::from_span(filename, content, &span[..span_end], message)
+}
+
+// The tags made here are used to test the other formats too
+#[cfg(test)]
+pub mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        parse::{
+            format_tag,
+            parse_input_file,
+        },
+        types::json::JsonFile,
+    };
+
+    fn json_document(glob: &str, shared: &TagSet, files: &[(&str, TagSet)]) -> String {
+        let document = JsonDocument {
+            version: SCHEMA_VERSION,
+            paths: None,
+            glob: Some(glob.to_string()),
+            shared: tags_to_json(shared, true),
+            files: files.iter()
+                .map(|(path, tag_set)| JsonFile { path: path.to_string(), tags: tags_to_json(tag_set, true) })
+                .collect(),
+        };
+        serde_json::to_string(&document).unwrap()
+    }
+
+    fn kiln_document(sections: &[Section]) -> String {
+        sections.iter()
+            .map(|section| {
+                let tags = section.tag_set.iter().flat_map(format_tag).collect::<Vec<_>>();
+                format!("[{}]\n{}\n", section.header, tags.join("\n"))
+            })
+            .collect()
+    }
+
+    // Values that have to be quoted or escaped somewhere along the way, like
+    // descriptions with ': ' in them, turn up far more often than they would
+    const VALUE: &str = "(: |\"|\\[|[^\u{0}]){0,12}";
+
+    // Pictures can't be given in a kiln file, only a path to one, so they're
+    // only made when asked for
+    pub fn tag_set(pictures: bool) -> impl Strategy<Value = TagSet> {
+        let ids = vec![TagId::TPE1, TagId::TALB, TagId::TIT2, TagId::TRCK, TagId::TCON];
+        let text = prop::collection::btree_map(prop::sample::select(ids), prop::collection::vec(VALUE, 1..3), 0..5);
+        let comments = prop::collection::btree_map(("[a-z]{3}", VALUE), VALUE, 0..3);
+        let extended = prop::collection::btree_map(VALUE, VALUE, 0..3);
+        let picture = ("image/(jpeg|png)", VALUE, prop::collection::vec(any::<u8>(), 0..16));
+        let pictures = prop::collection::btree_map(0u8..=20, picture, if pictures { 0..3 } else { 0..1 });
+
+        (text, comments, extended, pictures).prop_map(|(text, comments, extended, pictures)| {
+            let mut tag_set = text.into_iter()
+                .map(|(id, values)| TagPair::from_id(id, Content::Text(values.join("\0"))))
+                .collect::<TagSet>();
+            for ((lang, description), text) in comments {
+                let comment = Comment { lang, description, text };
+                tag_set.insert(TagPair::from_id(TagId::COMM, Content::Comment(comment)));
+            }
+            for (description, value) in extended {
+                let text = ExtendedText { description, value };
+                tag_set.insert(TagPair::from_id(TagId::TXXX, Content::ExtendedText(text)));
+            }
+            for (picture_type, (mime_type, description, data)) in pictures {
+                let picture = Picture { mime_type, picture_type: picture_type_from_u8(picture_type), description, data };
+                tag_set.insert(TagPair::from_id(TagId::APIC, Content::Picture(picture)));
+            }
+            tag_set
+        })
+    }
+
+    // JSON paths are literal and kiln headers are escaped, so both have to
+    // end up as the same header
+    #[test]
+    fn json_paths_become_escaped_headers() {
+        let tag_set = TagSet::from([TagPair::from_id(TagId::TIT2, Content::Text(String::from("x")))]);
+        let content = json_document("./*", &TagSet::new(), &[("track [1]?.mp3", tag_set.clone())]);
+        let from_json = parse_json_file("test.json", &content, None).unwrap();
+
+        let content = format!("[./*]\n\n[{}]\nTIT2 = x\n", Pattern::escape("track [1]?.mp3"));
+        let from_kiln = parse_input_file("test.kiln", &content, false, None).unwrap();
+
+        let headers = |document: &KilnDocument| document.sections.iter().map(|e| e.header.clone()).collect::<Vec<_>>();
+        assert_eq!(headers(&from_json), headers(&from_kiln));
+        assert_eq!(from_json.sections[1].tag_set, tag_set);
+    }
+
+    // Version 1 documents gave COMM and APIC as single objects
+    #[test]
+    fn single_comments_are_still_read() {
+        let content = r#"{"version": 1, "files": [{"path": "a.mp3", "tags": {"COMM": {"description": "d", "text": "t"}}}]}"#;
+        let document = parse_json_file("test.json", content, None).unwrap();
+        assert_eq!(document.sections[0].tag_set, TagSet::from([TagPair::from_str("COMM", "d: t").unwrap()]));
+    }
+
+    proptest! {
+        #[test]
+        fn any_tags_survive_json(shared in tag_set(true), file in tag_set(true)) {
+            let content = json_document("./*", &shared, &[("a.mp3", file.clone())]);
+            let from_json = parse_json_file("test.json", &content, None).unwrap();
+            prop_assert_eq!(&from_json.sections[0].tag_set, &shared);
+            prop_assert_eq!(&from_json.sections[1].tag_set, &file);
+        }
+
+        #[test]
+        fn any_tags_survive_json_then_kiln(shared in tag_set(false), file in tag_set(false)) {
+            let content = json_document("./*", &shared, &[("a.mp3", file.clone())]);
+            let from_json = parse_json_file("test.json", &content, None).unwrap();
+            prop_assert_eq!(&from_json.sections[0].tag_set, &shared);
+            prop_assert_eq!(&from_json.sections[1].tag_set, &file);
+
+            let content = kiln_document(&from_json.sections);
+            let from_kiln = parse_input_file("test.kiln", &content, false, None).unwrap();
+            prop_assert_eq!(from_kiln.sections.len(), 2);
+            prop_assert_eq!(&from_kiln.sections[0].tag_set, &shared);
+            prop_assert_eq!(&from_kiln.sections[1].tag_set, &file);
+        }
+
+        #[test]
+        fn any_tags_survive_kiln_then_json(shared in tag_set(false), file in tag_set(false)) {
+            let sections = vec![
+                Section { header: String::from("./*"), tag_set: shared.clone() },
+                Section { header: String::from("a.mp3"), tag_set: file.clone() },
+            ];
+            let from_kiln = parse_input_file("test.kiln", &kiln_document(&sections), false, None).unwrap();
+
+            let content = json_document(
+                &from_kiln.sections[0].header,
+                &from_kiln.sections[0].tag_set,
+                &[("a.mp3", from_kiln.sections[1].tag_set.clone())],
+            );
+            let from_json = parse_json_file("test.json", &content, None).unwrap();
+            prop_assert_eq!(&from_json.sections[0].tag_set, &shared);
+            prop_assert_eq!(&from_json.sections[1].tag_set, &file);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! kiln reads and writes id3 tags through plain text files.
+//!
+//! The library does everything the `kiln` command line tool does, short of
+//! printing: [`input`] reads and parses input files into sections, [`files`]
+//! reads tags from the files matched by a glob, and [`plan`] works out the
+//! changes needed to give files the tags their sections describe, and writes
+//! them out.
+
+pub mod files;
+
+pub mod index;
+
+pub mod input;
+
+pub mod json;
+
+pub mod normalize;
+
+pub mod order;
+
+pub mod parse;
+
+pub mod plan;
+
+pub mod query;
+
+pub mod structured;
+
+pub mod table;
+
+pub mod types;
@@ -1,30 +1,50 @@
 use clap::Parser;
 
+mod args;
+use args::{
+    Commands,
+    KilnArgs,
+};
+
 mod commands;
 use commands::{
+    apply::apply_plan_file,
+    compare::compare_tags,
+    copy::copy_tags,
+    diff::diff_tags,
+    from_path::tags_from_path,
+    index::manage_index,
     list::list_tags,
+    normalize::normalize_tags,
+    plan::plan_changes,
+    rename::rename_files,
+    replace::replace_tags,
+    search::search_tags,
     set::set_tags,
 };
 
-mod parse;
-
-mod types;
-use types::{
-    args::{
-        Commands,
-        KilnArgs,
-    },
-};
-
 fn main() {
     let args = KilnArgs::parse();
 
     let res = match args.command {
         Commands::List(args) => list_tags(args),
         Commands::Set(args) => set_tags(args),
+        Commands::Diff(args) => diff_tags(args),
+        Commands::Plan(args) => plan_changes(args),
+        Commands::Apply(args) => apply_plan_file(args),
+        Commands::Compare(args) => compare_tags(args),
+        Commands::Copy(args) => copy_tags(args),
+        Commands::FromPath(args) => tags_from_path(args),
+        Commands::Rename(args) => rename_files(args),
+        Commands::Search(args) => search_tags(args),
+        Commands::Replace(args) => replace_tags(args),
+        Commands::Normalize(args) => normalize_tags(args),
+        Commands::Index(args) => manage_index(args),
     };
 
+    // Scripts need to be able to tell that something went wrong
     if let Err(e) = res {
         eprintln!("{e}");
+        std::process::exit(1);
     }
 }
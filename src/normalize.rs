@@ -0,0 +1,367 @@
+use id3::frame::Content;
+use std::{
+    fs,
+    path::Path,
+};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::types::{
+    id3::{
+        TagId,
+        TagPair,
+        TagSet,
+    },
+    kiln::{
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+    },
+    normalize::NormalizeRules,
+};
+
+// What each featuring credit can be written as before it's normalized
+const FEAT_WORDS: [&str; 5] = ["feat", "feat.", "featuring", "ft", "ft."];
+
+const MONTHS: [&str; 12] = [
+    "january", "february", "march", "april", "may", "june",
+    "july", "august", "september", "october", "november", "december",
+];
+
+/// Read a TOML rules file for `kiln normalize`.
+pub fn read_rules(path: &Path) -> KilnResult<NormalizeRules> {
+    let content = fs::read_to_string(path)?;
+    match toml::from_str(&content) {
+        Ok(rules) => Ok(rules),
+        Err(e) => Err(KilnError::new(KilnErrorKind::Parse, format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Apply the rules to every text frame of a file. `track_total` is how many
+/// tracks there are in the file's album, if known, for the track rule to use
+/// when the file doesn't say. Values left empty are dropped, and so is a
+/// frame left with none at all.
+pub fn normalize_tag_set(tag_set: &TagSet, rules: &NormalizeRules, track_total: Option<usize>) -> TagSet {
+    tag_set.iter()
+        .filter_map(|tag| normalize_tag(tag, rules, track_total))
+        .collect()
+}
+
+/// How many tracks there are on an album, given the tags of every file on
+/// it. Totals the tracks already have are used if they all agree. Otherwise
+/// the files are counted, but only when their track numbers run from one
+/// without gaps or repeats, so that missing tracks or a second disc don't
+/// give the wrong total.
+pub fn track_total<'a>(album: impl IntoIterator<Item = &'a TagSet>) -> Option<usize> {
+    let mut numbers = Vec::new();
+    let mut totals = Vec::new();
+    for tag_set in album {
+        let track = tag_set.iter()
+            .find(|e| e.id == TagId::TRCK)
+            .and_then(|e| e.values().into_iter().next())?;
+        let (number, total) = match track.split_once('/') {
+            Some((number, total)) => (number, Some(total)),
+            None => (track.as_str(), None),
+        };
+
+        numbers.push(number.trim().parse::<usize>().ok()?);
+        if let Some(total) = total {
+            totals.push(total.trim().parse::<usize>().ok()?);
+        }
+    }
+
+    if let Some(total) = totals.first() {
+        return totals.iter().all(|e| e == total).then_some(*total);
+    }
+
+    numbers.sort_unstable();
+    match numbers.iter().enumerate().all(|(i, number)| *number == i + 1) {
+        true if !numbers.is_empty() => Some(numbers.len()),
+        _ => None,
+    }
+}
+
+/// Put a value into Unicode's composed form (NFC), so that letters with
+/// accents are always the same characters.
+pub fn compose_unicode(val: &str) -> String {
+    val.nfc().collect()
+}
+
+/// Strip whitespace from either end of a value.
+pub fn trim_whitespace(val: &str) -> String {
+    val.trim().to_string()
+}
+
+/// Write every featuring credit in a value, like 'ft.' or '(Featuring', in
+/// the given style.
+pub fn normalize_feat(val: &str, style: &str) -> String {
+    val.split(' ')
+        .map(|word| {
+            let start = word.find(char::is_alphanumeric).unwrap_or(word.len());
+            let (prefix, rest) = word.split_at(start);
+            match FEAT_WORDS.contains(&rest.to_lowercase().as_str()) {
+                true => format!("{}{}", prefix, style),
+                false => word.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Capitalise every word of a value, except for the exceptions, which are
+/// lower case unless they're the first or last word.
+pub fn title_case(val: &str, exceptions: &[String]) -> String {
+    let words = val.split(' ').collect::<Vec<_>>();
+    let last = words.len().saturating_sub(1);
+
+    words.iter()
+        .enumerate()
+        .map(|(i, word)| {
+            // Punctuation around a word doesn't stop it being an exception
+            let bare = word.trim_start_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+            let stripped = bare.trim_end_matches(|c: char| !c.is_alphanumeric());
+            let exception = exceptions.iter().any(|e| e.to_lowercase() == bare || e.to_lowercase() == stripped);
+
+            match exception && i != 0 && i != last {
+                true => word.to_lowercase(),
+                false => capitalise(word),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Pad a track number with zeros to the given width, adding the number of
+/// tracks if given one and the value doesn't already have it. Values that
+/// aren't track numbers are left as they are.
+pub fn format_track(val: &str, width: usize, total: Option<usize>) -> String {
+    let (number, existing_total) = match val.trim().split_once('/') {
+        Some((number, existing_total)) => (number, Some(existing_total)),
+        None => (val.trim(), None),
+    };
+
+    let number = match number.trim().parse::<usize>() {
+        Ok(number) => number,
+        Err(_) => return val.to_string(),
+    };
+    let total = match existing_total.map(|e| e.trim().parse::<usize>()) {
+        Some(Ok(existing_total)) => Some(existing_total),
+        Some(Err(_)) => return val.to_string(),
+        None => total,
+    };
+
+    match total {
+        Some(total) => format!("{:0width$}/{:0width$}", number, total, width = width),
+        None => format!("{:0width$}", number, width = width),
+    }
+}
+
+/// Write a date as ISO 8601, like '2024-03-09', '2024-03' or '2024'. Dates
+/// with the year first, dates with the month spelled out, and dates like
+/// '20240309' are understood. Anything else, including dates like
+/// '03/09/2024' that could be read either way, is left as it is.
+pub fn iso_date(val: &str) -> String {
+    let parts = val.trim()
+        .split(['-', '/', '.', ',', ' '])
+        .filter(|e| !e.is_empty())
+        .collect::<Vec<_>>();
+
+    let date = match parts.as_slice() {
+        [digits] if digits.len() == 8 && digits.chars().all(|c| c.is_ascii_digit()) => {
+            date_parts(&digits[..4], Some(&digits[4..6]), Some(&digits[6..]))
+        },
+        [year] => date_parts(year, None, None),
+        [year, month] if is_year(year) => date_parts(year, Some(month), None),
+        [month, year] if month_name(month).is_some() => date_parts(year, Some(month), None),
+        [year, month, day] if is_year(year) => date_parts(year, Some(month), Some(day)),
+        [day, month, year] if month_name(month).is_some() => date_parts(year, Some(month), Some(day)),
+        [month, day, year] if month_name(month).is_some() => date_parts(year, Some(month), Some(day)),
+        _ => None,
+    };
+
+    match date {
+        Some((year, None, _)) => format!("{:04}", year),
+        Some((year, Some(month), None)) => format!("{:04}-{:02}", year, month),
+        Some((year, Some(month), Some(day))) => format!("{:04}-{:02}-{:02}", year, month, day),
+        None => val.to_string(),
+    }
+}
+
+fn normalize_tag(tag: &TagPair, rules: &NormalizeRules, track_total: Option<usize>) -> Option<TagPair> {
+    // Comments and pictures aren't just text, so they're left alone
+    if !matches!(tag.val, Content::Text(_)) {
+        return Some(tag.clone());
+    }
+
+    let values = tag.values().iter()
+        .map(|val| normalize_value(tag.id, val, rules, track_total))
+        .filter(|val| !val.is_empty())
+        .collect::<Vec<_>>();
+
+    match values.is_empty() {
+        true => None,
+        false => Some(TagPair::from_id(tag.id, Content::Text(values.join("\0")))),
+    }
+}
+
+// The rules always apply in the same order, so that title case sees credits
+// that have already been normalized
+fn normalize_value(id: TagId, val: &str, rules: &NormalizeRules, track_total: Option<usize>) -> String {
+    let mut val = val.to_string();
+
+    if rules.nfc {
+        val = compose_unicode(&val);
+    }
+    if rules.trim {
+        val = trim_whitespace(&val);
+    }
+    if let Some(rule) = rules.feat.as_ref().filter(|e| e.fields.contains(&id)) {
+        val = normalize_feat(&val, &rule.style);
+    }
+    if let Some(rule) = rules.title_case.as_ref().filter(|e| e.fields.contains(&id)) {
+        val = title_case(&val, &rule.exceptions);
+    }
+    if let Some(rule) = rules.track.as_ref().filter(|_| id == TagId::TRCK) {
+        val = format_track(&val, rule.width, track_total.filter(|_| rule.total));
+    }
+    if rules.date.as_ref().is_some_and(|e| e.fields.contains(&id)) {
+        val = iso_date(&val);
+    }
+
+    val
+}
+
+// Only the first letter changes, so words like 'USA' and 'McCartney' keep
+// their capitals, and words that start with a number are left alone
+fn capitalise(word: &str) -> String {
+    match word.char_indices().find(|(_, c)| c.is_alphanumeric()) {
+        Some((i, c)) if c.is_alphabetic() => {
+            format!("{}{}{}", &word[..i], c.to_uppercase(), &word[i + c.len_utf8()..])
+        },
+        _ => word.to_string(),
+    }
+}
+
+fn date_parts(year: &str, month: Option<&str>, day: Option<&str>) -> Option<(u32, Option<u32>, Option<u32>)> {
+    if !is_year(year) {
+        return None;
+    }
+
+    let month = match month {
+        Some(month) => Some(month_name(month).or_else(|| month.parse().ok().filter(|e| (1..=12).contains(e)))?),
+        None => None,
+    };
+    let day = match day {
+        Some(day) => {
+            let day = day.trim_end_matches(|c: char| c.is_alphabetic());
+            Some(day.parse().ok().filter(|e| (1..=31).contains(e))?)
+        },
+        None => None,
+    };
+
+    Some((year.parse().ok()?, month, day))
+}
+
+fn is_year(val: &str) -> bool {
+    val.len() == 4 && val.chars().all(|c| c.is_ascii_digit())
+}
+
+// Months can be spelled out in full or cut short, like 'Mar' or 'Sept'
+fn month_name(val: &str) -> Option<u32> {
+    let val = val.to_lowercase();
+    if val.len() < 3 {
+        return None;
+    }
+
+    MONTHS.iter()
+        .position(|e| e.starts_with(&val))
+        .map(|e| e as u32 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::normalize::TrackRule;
+
+    fn tag_set(tags: &[(&str, &str)]) -> TagSet {
+        tags.iter().map(|(id, val)| TagPair::from_str(id, val).unwrap()).collect()
+    }
+
+    fn tracks(tracks: &[&str]) -> Vec<TagSet> {
+        tracks.iter().map(|e| tag_set(&[("TRCK", e)])).collect()
+    }
+
+    #[test]
+    fn accents_are_composed() {
+        assert_eq!(compose_unicode("Beyonce\u{301}"), "Beyonc\u{e9}");
+        assert_eq!(compose_unicode("Beyonc\u{e9}"), "Beyonc\u{e9}");
+    }
+
+    #[test]
+    fn whitespace_is_trimmed_from_the_ends() {
+        assert_eq!(trim_whitespace("  Opal  Drifters \t"), "Opal  Drifters");
+    }
+
+    #[test]
+    fn featuring_credits_are_written_one_way() {
+        assert_eq!(normalize_feat("Song ft. Someone", "feat."), "Song feat. Someone");
+        assert_eq!(normalize_feat("Song (Featuring Someone)", "feat."), "Song (feat. Someone)");
+        assert_eq!(normalize_feat("Song FEAT Someone", "with"), "Song with Someone");
+        assert_eq!(normalize_feat("Left Feet", "feat."), "Left Feet");
+    }
+
+    #[test]
+    fn title_case_keeps_exceptions_lower_inside() {
+        let exceptions = ["of".to_string(), "the".to_string()];
+        assert_eq!(title_case("the best of the USA", &exceptions), "The Best of the USA");
+        assert_eq!(title_case("what are you made of", &exceptions), "What Are You Made Of");
+        assert_eq!(title_case("mcCartney (of 2024)", &exceptions), "McCartney (of 2024)");
+    }
+
+    #[test]
+    fn track_numbers_are_padded() {
+        assert_eq!(format_track("3", 2, None), "03");
+        assert_eq!(format_track("3", 2, Some(12)), "03/12");
+        assert_eq!(format_track("3/9", 2, Some(12)), "03/09");
+        assert_eq!(format_track("A1", 2, Some(12)), "A1");
+        assert_eq!(format_track("3/x", 2, Some(12)), "3/x");
+    }
+
+    #[test]
+    fn dates_become_iso_only_when_unambiguous() {
+        assert_eq!(iso_date("2024"), "2024");
+        assert_eq!(iso_date("2024/3"), "2024-03");
+        assert_eq!(iso_date("March 9, 2024"), "2024-03-09");
+        assert_eq!(iso_date("9th Sept 2024"), "2024-09-09");
+        assert_eq!(iso_date("20240309"), "2024-03-09");
+        assert_eq!(iso_date("03/09/2024"), "03/09/2024");
+        assert_eq!(iso_date("2024-13-01"), "2024-13-01");
+    }
+
+    // Albums cut short by a glob, or with a second disc in the same
+    // directory, mustn't be given a total that isn't theirs
+    #[test]
+    fn track_totals_come_only_from_whole_albums() {
+        assert_eq!(track_total(&tracks(&["2", "1", "3"])), Some(3));
+        assert_eq!(track_total(&tracks(&["1", "2/9"])), Some(9));
+        assert_eq!(track_total(&tracks(&["1/9", "2/10"])), None);
+        assert_eq!(track_total(&tracks(&["1", "3"])), None);
+        assert_eq!(track_total(&tracks(&["1", "2", "1", "2"])), None);
+        assert_eq!(track_total(&[tag_set(&[("TRCK", "1")]), tag_set(&[("TIT2", "No Track")])]), None);
+        assert_eq!(track_total(&[]), None);
+    }
+
+    #[test]
+    fn rules_apply_to_every_text_frame() {
+        let rules = NormalizeRules {
+            trim: true,
+            track: Some(TrackRule { width: 2, total: true }),
+            ..Default::default()
+        };
+        let tags = tag_set(&[("TIT2", " Opal "), ("TRCK", "4"), ("TALB", "  ")]);
+
+        let normalized = normalize_tag_set(&tags, &rules, Some(9));
+        assert_eq!(normalized, tag_set(&[("TIT2", "Opal"), ("TRCK", "04/09")]));
+        let normalized = normalize_tag_set(&tags, &rules, None);
+        assert_eq!(normalized, tag_set(&[("TIT2", "Opal"), ("TRCK", "04")]));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    cmp::Ordering,
+    path::Path,
+};
+
+use crate::{
+    files::TagCache,
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+        },
+        kiln::{
+            Diff,
+            FileDiff,
+            KilnResult,
+        },
+        options::FrameOrder,
+    },
+};
+
+// The frames people look for first, in the order they expect them
+const PREFERRED_ORDER: [TagId; 4] = [TagId::TPE1, TagId::TALB, TagId::TRCK, TagId::TIT2];
+
+/// Compare paths the way a person would, so that track 2 comes before track 10.
+pub fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    natord::compare(&a.to_string_lossy(), &b.to_string_lossy())
+}
+
+/// The key to sort a frame by in the given order. For `FrameOrder::File`,
+/// `file_order` holds the ids of the frames in the order the file has them.
+/// Frames the order doesn't mention come after the rest, by id.
+pub fn frame_key(id: TagId, order: FrameOrder, file_order: &[TagId]) -> (usize, String) {
+    let position = match order {
+        FrameOrder::Id => None,
+        FrameOrder::Preferred => PREFERRED_ORDER.iter().position(|e| *e == id),
+        FrameOrder::File => file_order.iter().position(|e| *e == id),
+    };
+
+    (position.unwrap_or(usize::MAX), format!("{:?}", id))
+}
+
+/// Sort tags into the given frame order, as described for `frame_key`. Frames
+/// with the same id, like TXXX, are sorted by their values.
+pub fn sort_tags<'a>(
+    tags: impl IntoIterator<Item = &'a TagPair>,
+    order: FrameOrder,
+    file_order: &[TagId],
+) -> Vec<&'a TagPair> {
+    let mut tags = tags.into_iter().collect::<Vec<_>>();
+    tags.sort_by_key(|e| (frame_key(e.id, order, file_order), e.values()));
+    tags
+}
+
+/// Sort the changes for many files, so that the files are in natural order and
+/// each file's changes are in the given frame order. File order comes from the
+/// frames as they are in each file now, read through the cache.
+pub fn sort_diff(diff: &mut [FileDiff], order: FrameOrder, cache: &mut TagCache) -> KilnResult<()> {
+    diff.sort_by(|a, b| natural_cmp(&a.filepath, &b.filepath));
+
+    for filediff in diff {
+        let file_order = match order {
+            FrameOrder::File => cache.frame_order(&filediff.filepath)?,
+            _ => Vec::new(),
+        };
+        filediff.diffs.sort_by_key(|e| {
+            let tag = match e {
+                Diff::Add(tag) | Diff::Delete(tag) | Diff::Modify(tag, _) => tag,
+            };
+            (frame_key(tag.id, order, &file_order), tag.values())
+        });
+    }
+
+    Ok(())
+}
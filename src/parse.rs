@@ -1,18 +1,25 @@
 use nom::{
+    branch::alt,
     bytes::complete::{
         is_a,
         tag,
         take_till,
-        take_while,
+        take_while_m_n,
     },
     character::complete::{
         alphanumeric0 as alphanumeric,
+        alphanumeric1,
         char,
+        line_ending,
+        not_line_ending,
         space0 as space,
     },
     combinator::{
+        consumed,
         map,
+        map_opt,
         opt,
+        value,
     },
     error::{
         VerboseError,
@@ -22,13 +29,16 @@ use nom::{
     sequence::{
         delimited,
         pair,
+        preceded,
         terminated,
         tuple,
     },
     IResult,
 };
+use std::path::Path;
 
 use crate::types::{
+    diagnostic::Diagnostic,
     id3::{
         TagPair,
         TagSet,
@@ -36,112 +46,435 @@ use crate::types::{
     kiln::{
         KilnError,
         KilnErrorKind,
+        KilnDocument,
         KilnResult,
+        PathMode,
         Section,
     },
 };
 
-pub fn parse_input_file(content: &str) -> KilnResult<Vec<Section>> {
-    let (remaining, parsed) = match sections(content) {
-        Ok((remaining, parsed)) => (remaining, parsed),
-        Err(nom::Err::Failure(e)) => {
-            if !e.errors.is_empty() {
-                match e.errors[0] {
-                    (path, VerboseErrorKind::Context("Image")) => {
-                        return Err(KilnError::new(KilnErrorKind::Image, format!("Bad image: {}", path)))
-                    },
-                    (tag, VerboseErrorKind::Context("ID3")) => {
-                        return Err(KilnError::new(KilnErrorKind::ID3, format!("{} is not a valid id3 tag for kiln", tag)))
-                    },
-                    (item, VerboseErrorKind::Context(context)) => {
-                        return Err(KilnError::new(KilnErrorKind::Parse, format!("{}: {}", context, item)))
-                    },
-                    _ => panic!("Something has gone horribly wrong here! {:?}", e.errors[0])
-                }
-            } else {
+// The grammar only concerns itself with syntax, and hands back slices of the
+// input so that any semantic errors can be reported with their position
+struct RawDocument<'a> {
+    directives: Vec<(&'a str, &'a str)>,
+    sections: Vec<RawSection<'a>>,
+    errors: Vec<SyntaxError<'a>>,
+}
+
+// Lines the grammar doesn't recognise at all are skipped when lenient, but
+// mistakes within a line it does recognise, like a bad escape, never are
+struct SyntaxError<'a> {
+    span: &'a str,
+    message: String,
+    unrecognised: bool,
+}
+
+struct RawSection<'a> {
+    header: &'a str,
+    tag_pairs: Vec<RawTagPair<'a>>,
+}
+
+struct RawTagPair<'a> {
+    key: &'a str,
+    span: &'a str,
+    val: String,
+}
 
-                return Err(KilnError::new(KilnErrorKind::Parse, "LMAO".to_string()))
+/// Parse a kiln file. With `lenient`, lines that can't be parsed at all are
+/// only warned about, and the warnings are returned with the document.
+pub fn parse_input_file(filename: &str, content: &str, lenient: bool, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    let raw_document = document(content);
+
+    // Lines that couldn't be parsed are errors, unless we're asked to be
+    // lenient, in which case unrecognised lines are only warned about
+    let mut diagnostics = Vec::new();
+    let mut warnings = Vec::new();
+    for error in raw_document.errors {
+        let diagnostic = Diagnostic::from_span(filename, content, error.span, error.message);
+        match error.unrecognised && lenient {
+            true => warnings.push(diagnostic.warning()),
+            false => diagnostics.push(diagnostic),
+        }
+    }
+
+    let mut path_mode = PathMode::default();
+    for (key, val) in raw_document.directives {
+        match (key, val) {
+            ("paths", "file") => path_mode = PathMode::File,
+            ("paths", "cwd") => path_mode = PathMode::Cwd,
+            ("paths", _) => diagnostics.push(
+                Diagnostic::from_span(filename, content, val, format!("unknown path mode '{}', expected 'file' or 'cwd'", val))
+            ),
+            _ => diagnostics.push(
+                Diagnostic::from_span(filename, content, key, format!("unknown directive '{}'", key))
+            ),
+        }
+    }
+
+    let dir = path_mode.base_dir(dir);
+    let mut sections = Vec::new();
+    for raw_section in raw_document.sections {
+        let mut tag_pairs: Vec<TagPair> = Vec::new();
+        for raw in raw_section.tag_pairs {
+            match TagPair::from_str_in(raw.key, &raw.val, dir) {
+                // Repeated keys within a section build up a multi-valued text
+                // frame, or give another frame for those a file can have
+                // several of, like a COMM for each description
+                Ok(tag_pair) => match tag_pairs.iter_mut().find(|e| e.same_frame(&tag_pair)) {
+                    Some(existing) => if let Err(e) = existing.append(tag_pair) {
+                        diagnostics.push(Diagnostic::from_span(filename, content, raw.key, e.message));
+                    },
+                    None => tag_pairs.push(tag_pair),
+                },
+                Err(e) => {
+                    let diagnostic = match e.kind {
+                        KilnErrorKind::Image => Diagnostic::from_span(filename, content, raw.span, format!("bad image: {}", e.message)),
+                        _ => Diagnostic::from_span(filename, content, raw.key, e.message),
+                    };
+                    diagnostics.push(diagnostic);
+                },
             }
+        }
+
+        let tag_set = tag_pairs.into_iter().collect::<TagSet>();
+        sections.push(Section { header: String::from(raw_section.header), tag_set });
+    }
+
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|e| (e.line, e.column));
+        return Err(KilnError::from_diagnostics(filename, diagnostics));
+    }
+
+    Ok(KilnDocument { path_mode, sections, preserved_tags: Vec::new(), warnings })
+}
+
+/// Turn an error from one of the grammars built with nom into diagnostics,
+/// pointing at wherever it went wrong.
+pub fn grammar_error(filename: &str, content: &str, e: nom::Err<VerboseError<&str>>) -> KilnError {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => {
+            let diagnostics = e.errors.first()
+                .map(|(span, kind)| {
+                    let message = match kind {
+                        VerboseErrorKind::Context(context) => context.to_string(),
+                        _ => "unexpected input".to_string(),
+                    };
+                    Diagnostic::from_span(filename, content, span, message)
+                })
+                .into_iter()
+                .collect();
+            KilnError::from_diagnostics(filename, diagnostics)
         },
-        Err(e) => return Err(KilnError::new(KilnErrorKind::Parse, e.to_string())),
-    };
+        e => KilnError::new(KilnErrorKind::Parse, e.to_string()),
+    }
+}
+
+// Parsing goes a line at a time, and a line that can't be parsed is recorded
+// and skipped, so that one mistake doesn't hide the ones after it
+fn document(input: &str) -> RawDocument<'_> {
+    let mut document = RawDocument { directives: Vec::new(), sections: Vec::new(), errors: Vec::new() };
 
-    // If we still have remaining input, alert the user to this
-    if remaining.len() > 0 {
-        println!("WARN: Could not consume all the input! Here is what remains:");
-        println!("\n{remaining}\n");
+    // Tags under a header that couldn't be parsed are still checked, but they
+    // mustn't end up in the section before it
+    let mut broken_header = false;
+    let mut i = input;
+    loop {
+        i = ignored(i).map_or(i, |(rest, _)| rest);
+        if i.is_empty() { break; }
+
+        let line = i.lines().next().unwrap_or_default().trim_end();
+        let result = match (i.chars().next(), document.sections.last_mut()) {
+            (Some('['), _) => {
+                let result = header(i);
+                broken_header = result.is_err();
+                result.map(|(rest, header)| {
+                    document.sections.push(RawSection { header, tag_pairs: Vec::new() });
+                    rest
+                })
+            },
+            (Some('@'), None) if !broken_header => directive(i).map(|(rest, directive)| {
+                document.directives.push(directive);
+                rest
+            }),
+            (Some('@'), _) => Err(unrecognised(line, "directives must come before the first header")),
+            (_, _) if broken_header => tag_pair(i).map(|(rest, _)| rest),
+            (_, Some(section)) => tag_pair(i).map(|(rest, tag_pair)| {
+                section.tag_pairs.push(tag_pair);
+                rest
+            }),
+            (_, None) => Err(unrecognised(line, "tags must come after a header like '[./*]'")),
+        };
+
+        match result {
+            Ok(rest) => i = rest,
+            Err(e) => {
+                // Failures come from lines that were recognised, and say what's wrong with them
+                let (unrecognised, errors) = match e {
+                    nom::Err::Failure(e) => (false, e.errors),
+                    nom::Err::Error(e) => (true, e.errors),
+                    nom::Err::Incomplete(_) => (true, Vec::new()),
+                };
+                let (span, message) = match errors.first() {
+                    Some((span, VerboseErrorKind::Context(context))) => (*span, context.to_string()),
+                    _ => (line, "could not parse this line".to_string()),
+                };
+                document.errors.push(SyntaxError { span, message, unrecognised });
+                i = i.split_once('\n').map_or("", |(_, rest)| rest);
+            },
+        }
     }
 
-    Ok(parsed)
+    document
 }
 
-fn sections(input: &str) -> IResult<&str, Vec<Section>, VerboseError<&str>> {
-    many(section)(input)
+// Directives like '@paths = cwd' may only appear before the first section
+fn directive(input: &str) -> IResult<&str, (&str, &str), VerboseError<&str>> {
+    let (i, key) = preceded(char('@'), alphanumeric1)(input)?;
+    let (i, _) = tuple((opt(space), tag("="), opt(space)))(i)?;
+    let (i, val) = map(not_line_ending, str::trim_end)(i)?;
+    let (i, _) = opt(is_a(" \r\n"))(i)?;
+
+    Ok((i, (key, val)))
 }
 
-fn section(input: &str) -> IResult<&str, Section, VerboseError<&str>> {
-    map(
-        pair(header, tag_set),
-        |(h, ts)| Section { header: String::from(h), tag_set: ts }
+// Blank lines and comments may appear anywhere between headers and tags
+fn ignored(input: &str) -> IResult<&str, (), VerboseError<&str>> {
+    value(
+        (),
+        many(alt((
+            value((), pair(is_a(" \t"), opt(line_ending))),
+            value((), line_ending),
+            value((), tuple((char('#'), not_line_ending, opt(line_ending)))),
+        ))),
     )(input)
 }
 
+// Headers run up to the last ']' on the line, since escaped globs and file
+// names can contain brackets of their own
 fn header(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
     terminated(
-        delimited(char('['), take_while(|c| c != ']'), char(']')),
+        preceded(
+            char('['),
+            map_opt(not_line_ending, |line: &str| line.trim_end().strip_suffix(']')),
+        ),
         opt(is_a(" \r\n")),
     )(input)
 }
 
-fn tag_set(input: &str) -> IResult<&str, TagSet, VerboseError<&str>> {
-    map(
-        many(tag_pair),
-        |vec| vec.into_iter().collect(),
-    )(input)
-}
-
-fn tag_pair(input: &str) -> IResult<&str, TagPair, VerboseError<&str>> {
+fn tag_pair(input: &str) -> IResult<&str, RawTagPair<'_>, VerboseError<&str>> {
     let (i, key) = alphanumeric(input)?;
     let (i, _) = tuple((opt(space), tag("="), opt(space)))(i)?;
-    let (i, val) = take_till(|c| c == '\n')(i)?;
+    let (i, (span, val)) = consumed(
+        alt((
+            quoted_value,
+            map(take_till(|c| c == '\r' || c == '\n'), String::from),
+        ))
+    )(i)?;
+
+    // Only whitespace may follow the closing quote on a line
+    let (i, _) = opt(space)(i)?;
+    if !i.is_empty() && !i.starts_with(['\r', '\n']) {
+        return Err(failure(i, "unexpected input after quoted value"));
+    }
     let (i, _) = opt(is_a(" \r\n"))(i)?;
 
-    match TagPair::from_str(key, val) {
-        Ok(tag_pair) => Ok((i, tag_pair)),
-        Err(e) => match e.kind {
-            KilnErrorKind::Image => {
-                Err(
-                    nom::Err::Failure(
-                        VerboseError {
-                            errors: vec![(
-                                val,
-                                VerboseErrorKind::Context("Image"),
-                            )]
-                        }
-                    )
-                )
-            },
-            KilnErrorKind::ID3 => {
-                Err(
-                    nom::Err::Failure(
-                        VerboseError {
-                            errors: vec![(
-                                key,
-                                VerboseErrorKind::Context("ID3"),
-                            )]
-                        }
-                    )
-                )
+    Ok((i, RawTagPair { key, span, val }))
+}
+
+/// Parse a quoted value, which may contain anything, so long as quotes,
+/// backslashes and control characters are escaped.
+pub fn quoted_value(input: &str) -> IResult<&str, String, VerboseError<&str>> {
+    let (mut i, _) = char('"')(input)?;
+    let mut val = String::new();
+
+    loop {
+        let mut chars = i.chars();
+        match chars.next() {
+            None | Some('\r') | Some('\n') => return Err(failure(input, "unterminated quoted value")),
+            Some('"') => return Ok((chars.as_str(), val)),
+            Some('\\') => {
+                let (rest, c) = escape_sequence(i)?;
+                val.push(c);
+                i = rest;
             },
-            _ => {
-                Err(
-                    nom::Err::Failure(
-                        VerboseError {
-                            errors: vec![]
-                        }
-                    )
-                )
+            Some(c) => {
+                val.push(c);
+                i = chars.as_str();
             },
-        },
+        }
+    }
+}
+
+fn escape_sequence(input: &str) -> IResult<&str, char, VerboseError<&str>> {
+    let unicode = map_opt(
+        delimited(
+            tag("\\u{"),
+            take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
+            char('}'),
+        ),
+        |hex| u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+    );
+
+    let result: IResult<&str, char, VerboseError<&str>> = alt((
+        value('\\', tag("\\\\")),
+        value('"', tag("\\\"")),
+        value('\n', tag("\\n")),
+        value('\r', tag("\\r")),
+        value('\t', tag("\\t")),
+        value('\0', tag("\\0")),
+        unicode,
+    ))(input);
+
+    result.map_err(|_| {
+        // Point at just the backslash and the character following it
+        let end = input.char_indices().nth(2).map_or(input.len(), |(i, _)| i);
+        failure(&input[..end], "invalid escape sequence")
+    })
+}
+
+/// An error that stops the grammar where it is, with a message for the span.
+pub fn failure<'a>(span: &'a str, context: &'static str) -> nom::Err<VerboseError<&'a str>> {
+    nom::Err::Failure(
+        VerboseError {
+            errors: vec![(span, VerboseErrorKind::Context(context))]
+        }
+    )
+}
+
+// A line that isn't what it should be where it is, but that lenient parsing
+// can skip
+fn unrecognised<'a>(span: &'a str, context: &'static str) -> nom::Err<VerboseError<&'a str>> {
+    nom::Err::Error(
+        VerboseError {
+            errors: vec![(span, VerboseErrorKind::Context(context))]
+        }
+    )
+}
+
+/// Quote a value if it needs to be, so that it's read back in verbatim.
+// The inverse of the grammar above, used when listing tags so that whatever
+// we print can be read back in verbatim
+pub fn quote_value(val: &str) -> String {
+    match needs_quotes(val) {
+        true => format!("\"{}\"", escape_value(val)),
+        false => val.to_string(),
+    }
+}
+
+/// Whether a value has to be quoted to be read back in verbatim.
+pub fn needs_quotes(val: &str) -> bool {
+    val.starts_with('"')
+        || val.starts_with(char::is_whitespace)
+        || val.ends_with(char::is_whitespace)
+        || val.chars().any(char::is_control)
+}
+
+/// Escape the characters a quoted value can't hold as they are.
+pub fn escape_value(val: &str) -> String {
+    let mut escaped = String::new();
+    for c in val.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Format a tag as it appears in a kiln file, with multi-valued frames
+/// written out as one line per value.
+pub fn format_tag(tag: &TagPair) -> Vec<String> {
+    tag.values()
+        .iter()
+        .map(|val| format!("{:?} = {}", tag.id, quote_value(val)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use glob::Pattern;
+    use id3::frame::Content;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::types::id3::TagId;
+
+    // Paths are escaped when listed, and headers run to the last ']', so
+    // names with brackets, stars and question marks come back as themselves
+    #[test]
+    fn escaped_headers_match_only_their_own_file() {
+        let names = ["track [1].mp3", "what?.mp3", "*.mp3", "[a]] b [c.mp3", "a]b.mp3"];
+        for name in names {
+            let content = format!("[{}]\nTIT2 = x\n", Pattern::escape(name));
+            let document = parse_input_file("test.kiln", &content, false, None).unwrap();
+            let header = &document.sections[0].header;
+
+            let pattern = Pattern::new(header).unwrap();
+            for other in names {
+                assert_eq!(pattern.matches(other), other == name, "{} against {}", header, other);
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_parsing_returns_warnings() {
+        let content = "[a.mp3]\nTIT2 = x\nnot a tag\n";
+        assert!(parse_input_file("test.kiln", content, false, None).is_err());
+
+        let document = parse_input_file("test.kiln", content, true, None).unwrap();
+        assert_eq!(document.sections.len(), 1);
+        assert_eq!(document.warnings.len(), 1);
+        assert_eq!(document.warnings[0].line, 3);
+    }
+
+    #[test]
+    fn headers_run_to_the_last_bracket() {
+        let document = parse_input_file("test.kiln", "[live [2019]] ]\nTIT2 = x\n", false, None).unwrap();
+        assert_eq!(document.sections[0].header, "live [2019]] ");
+    }
+
+    // Text frames hold several values, but frames a file can have several of
+    // are kept apart, as long as no two of them are the same frame
+    #[test]
+    fn repeated_keys_give_separate_frames() {
+        let content = "[a.mp3]\nCOMM = hi\nCOMM = iTunNORM: 0000\nTXXX = A: 1\nTXXX = B: 2\nTPE1 = x\nTPE1 = y\n";
+        let document = parse_input_file("test.kiln", content, false, None).unwrap();
+        let tag_set = &document.sections[0].tag_set;
+        assert_eq!(tag_set.len(), 5);
+        assert!(tag_set.contains(&TagPair::from_id(TagId::TPE1, Content::Text(String::from("x\0y")))));
+
+        let e = parse_input_file("test.kiln", "[a.mp3]\nCOMM = a: 1\nCOMM = a: 2\n", false, None).unwrap_err();
+        assert!(e.to_string().contains("one COMM frame for each language and description"), "{}", e);
+    }
+
+    proptest! {
+        #[test]
+        fn any_value_survives_quoting(val in any::<String>()) {
+            let quoted = quote_value(&val);
+            if needs_quotes(&val) {
+                prop_assert_eq!(quoted_value(&quoted).ok(), Some(("", val)));
+            } else {
+                prop_assert_eq!(quoted, val);
+            }
+        }
+
+        // Values of multi-valued frames are listed a line each, and joined back up
+        #[test]
+        fn any_frame_survives_list_then_parse(values in prop::collection::vec("[^\u{0}]*", 1..4)) {
+            let val = values.join("\0");
+            let tag = TagPair::from_id(TagId::TIT2, Content::Text(val.clone()));
+            let content = format!("[a.mp3]\n{}\n", format_tag(&tag).join("\n"));
+
+            let document = parse_input_file("test.kiln", &content, false, None).unwrap();
+            let parsed = document.sections[0].tag_set.iter().collect::<Vec<_>>();
+            prop_assert_eq!(parsed, vec![&tag]);
+        }
     }
 }
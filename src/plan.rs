@@ -0,0 +1,518 @@
+use glob::glob;
+use id3::{
+    Frame,
+    Tag,
+    TagLike,
+    Version,
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fs,
+};
+
+use crate::{
+    files::{
+        path_errors,
+        TagCache,
+    },
+    json::{
+        diagnostic_at,
+        json_to_tag,
+        tag_to_exact_json,
+    },
+    types::{
+        id3::{
+            TagId,
+            TagPair,
+            TagSet,
+        },
+        json::{
+            JsonChange,
+            SCHEMA_VERSION,
+        },
+        kiln::{
+            Diff,
+            FileDiff,
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            Section,
+        },
+        options::Id3Version,
+        plan::{
+            Plan,
+            PlannedFile,
+        },
+    },
+};
+
+/// Work out the changes needed to give every file matched by the sections
+/// the tags they describe, leaving alone any tags that should be preserved.
+/// Files are read through the cache, so it can be used again to write them.
+pub fn calculate_diff(
+    sections: Vec<Section>,
+    preserved_tags: Vec<TagId>,
+    cache: &mut TagCache,
+) -> KilnResult<Vec<FileDiff>> {
+    let old_tags = get_old_tags_from_sections(&sections, cache)?;
+    let new_tags = get_new_tags_from_sections(&sections)?;
+
+    let mut diffs = Vec::new();
+
+    for (header, new_set) in new_tags {
+        let old_set = old_tags.get(&header).unwrap();
+        diffs.push(diff_tag_sets(header, old_set, &new_set, &preserved_tags));
+    }
+
+    Ok(diffs)
+}
+
+/// Work out the changes that turn one set of tags into another.
+pub fn diff_tag_sets(header: String, old_set: &TagSet, new_set: &TagSet, preserved_tags: &[TagId]) -> FileDiff {
+    let mut filediff = FileDiff::from(header);
+
+    // Convert our Sets into Vecs for ease of use
+    let new_set = new_set.iter().collect::<Vec<_>>();
+    let old_set = old_set.iter().collect::<Vec<_>>();
+
+    // First we check for new and modified tags
+    for new_tag in &new_set {
+        if let Some(old_tag) = find_frame(&old_set, new_tag) {
+            if old_tag.val != new_tag.val {
+                filediff.diffs.push(
+                    Diff::Modify(
+                        TagPair::from_id(old_tag.id, old_tag.val),
+                        TagPair::from_id(new_tag.id, new_tag.val.clone())
+                    )
+                );
+            }
+        } else {
+            filediff.diffs.push(
+                Diff::Add(
+                    TagPair::from_id(new_tag.id, new_tag.val.clone())
+                )
+            );
+        }
+    }
+
+    // Then we double back to look for deleted tags
+    for old_tag in old_set {
+        if find_frame(&new_set, old_tag).is_some() {
+            continue;
+        } else {
+            // If we want to preserve this tag, then don't even create the diff
+            if preserved_tags.contains(&old_tag.id) {
+                continue;
+            }
+            filediff.diffs.push(
+                Diff::Delete(
+                    TagPair::from_id(old_tag.id, old_tag.val.clone())
+                )
+            );
+        }
+    }
+
+    filediff
+}
+
+/// Read the current tags of every file matched by the sections. It's an error
+/// for a section to match no files at all, since that's almost always a header
+/// resolved against the wrong directory.
+pub fn get_old_tags_from_sections(sections: &Vec<Section>, cache: &mut TagCache) -> KilnResult<HashMap<String, TagSet>> {
+    let mut entries_by_path = Vec::new();
+    let mut unmatched = Vec::new();
+
+    for section in sections {
+        let matched = entries_by_path.len();
+
+        let entries = match glob(&section.header) {
+            Ok(entries) => entries,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+            };
+
+            if let Some(ext) = entry.extension() {
+                // For now we only want to operate on mp3 files.
+                if ext != "mp3" { continue; }
+
+                let path_string = entry.clone().into_os_string().into_string().unwrap();
+
+                entries_by_path.push((path_string, entry));
+            }
+        }
+
+        if entries_by_path.len() == matched {
+            unmatched.push(format!("[{}]", section.header));
+        }
+    }
+
+    match unmatched.len() {
+        0 => {},
+        1 => return Err(KilnError::new(KilnErrorKind::Glob, format!("no files match the header {}", unmatched[0]))),
+        count => return Err(KilnError::new(
+            KilnErrorKind::Glob,
+            format!("{} headers match no files:\n{}", count, unmatched.join("\n"))
+        )),
+    }
+
+    // Read every file up front, so they can be read in parallel
+    let filepaths = entries_by_path.iter().map(|(_, entry)| entry.clone()).collect::<Vec<_>>();
+    cache.read_all(&filepaths)?;
+
+    let mut tag_map = HashMap::new();
+    for (path_string, entry) in entries_by_path {
+        // If we've already added this to the map, ignore it
+        if tag_map.contains_key(&path_string) { continue; }
+
+        tag_map.insert(path_string, cache.tag_set(&entry)?);
+    }
+
+    Ok(tag_map)
+}
+
+/// Gather the tags the sections give each file they match.
+pub fn get_new_tags_from_sections(sections: &Vec<Section>) -> KilnResult<HashMap<String, TagSet>> {
+    let mut tag_map: HashMap<String, TagSet> = HashMap::new();
+
+    for section in sections {
+        let entries = match glob(&section.header) {
+            Ok(entries) => entries,
+            Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Err(KilnError::new(KilnErrorKind::Glob, e.to_string())),
+            };
+
+            if let Some(ext) = entry.extension() {
+                // For now we only want to operate on mp3 files.
+                if ext != "mp3" { continue; }
+
+                let path_string = entry.clone().into_os_string().into_string().unwrap();
+
+                // If we've already added this, then append to the tag_set
+                let mut new_tag_set = section.tag_set.clone();
+                if let Some(tag_set) = tag_map.get(&path_string) {
+                    new_tag_set.extend(tag_set.clone());
+                }
+                tag_map.insert(path_string, new_tag_set);
+            }
+        }
+    }
+
+    Ok(tag_map)
+}
+
+fn find_frame(vec: &Vec<&TagPair>, frame: &TagPair) -> Option<TagPair> {
+    for tag in vec {
+        if tag.same_frame(frame) {
+            return Some((*tag).clone());
+        }
+    }
+
+    None
+}
+
+/// Write the changes for one file to it, with the given version of id3. The
+/// changes are made to the tag in the cache, which is read first if need be.
+pub fn write_file_diff(filediff: FileDiff, id3_version: Id3Version, cache: &mut TagCache) -> KilnResult<()> {
+    let tag = cache.take(&filediff.filepath)?;
+    write_tag(tag, filediff, id3_version)
+}
+
+/// Write the changes for many files at once, spread across rayon's thread
+/// pool. Every file is written even if some of them fail, and the result for
+/// each comes back in the same order as the changes.
+pub fn write_file_diffs(diff: Vec<FileDiff>, id3_version: Id3Version, cache: &mut TagCache) -> Vec<KilnResult<()>> {
+    let filepaths = diff.iter().map(|e| e.filepath.clone()).collect::<Vec<_>>();
+
+    // A file that can't be read shouldn't stop the rest, so if reading them
+    // all at once fails, each one finds out for itself below
+    let _ = cache.read_all(&filepaths);
+    let tags = filepaths.iter()
+        .map(|e| cache.take(e))
+        .collect::<Vec<_>>();
+
+    diff.into_par_iter()
+        .zip(tags)
+        .map(|(filediff, tag)| write_tag(tag?, filediff, id3_version))
+        .collect()
+}
+
+fn write_tag(mut tag: Tag, filediff: FileDiff, id3_version: Id3Version) -> KilnResult<()> {
+    // The id3 crate takes care of joining multi-valued frames with '/' when
+    // writing anything older than 2.4, since null separators aren't allowed
+    let version = match id3_version {
+        Id3Version::V23 => Version::Id3v23,
+        Id3Version::V24 => Version::Id3v24,
+    };
+
+    for change in filediff.diffs {
+        match change {
+            Diff::Add(tag_pair) => {
+                tag.add_frame(
+                    Frame::with_content(
+                        format!("{:?}", tag_pair.id),
+                        tag_pair.val
+                    )
+                );
+            },
+            Diff::Delete(tag_pair) => remove_frame(&mut tag, &tag_pair),
+            // The new frame is the same frame as the old one, but the old one
+            // goes first anyway, so the file never ends up with both
+            Diff::Modify(old, tag_pair) => {
+                remove_frame(&mut tag, &old);
+                tag.add_frame(
+                    Frame::with_content(
+                        format!("{:?}", tag_pair.id),
+                        tag_pair.val
+                    )
+                );
+            }
+        }
+    }
+
+    match tag.write_to_path(filediff.filepath, version) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::ID3, e.to_string())),
+    }
+}
+
+// Take just the one frame out of a tag, since a file can have several with the
+// same id, like a COMM for each language and description
+fn remove_frame(tag: &mut Tag, tag_pair: &TagPair) {
+    for frame in tag.remove(format!("{:?}", tag_pair.id)) {
+        if !TagPair::from_id(tag_pair.id, frame.content().clone()).same_frame(tag_pair) {
+            tag.add_frame(frame);
+        }
+    }
+}
+
+/// Turn the changes worked out by `calculate_diff` into a plan. Files with no
+/// changes are left out. If `hash` is set, the hash of each file as it is now
+/// is recorded, so `check_plan` can tell if it changes before it's applied;
+/// that means reading each file in full, so it's best left off otherwise.
+pub fn make_plan(diff: &[FileDiff], hash: bool, cache: &mut TagCache) -> KilnResult<Plan> {
+    let mut files = Vec::new();
+
+    for filediff in diff {
+        if filediff.diffs.is_empty() { continue; }
+
+        // Plans can be applied from anywhere, so their paths are absolute
+        let path = fs::canonicalize(&filediff.filepath)?;
+        let changes = filediff.diffs.iter()
+            .map(|diff| match diff {
+                Diff::Add(tag) => JsonChange::Add { id: tag.id, new: tag_to_exact_json(tag) },
+                Diff::Delete(tag) => JsonChange::Delete { id: tag.id, old: tag_to_exact_json(tag) },
+                Diff::Modify(old, new) => JsonChange::Modify {
+                    id: old.id,
+                    old: tag_to_exact_json(old),
+                    new: tag_to_exact_json(new),
+                },
+            })
+            .collect();
+
+        let sha256 = if hash { Some(cache.hash(&path)?) } else { None };
+        files.push(PlannedFile { sha256, path, changes });
+    }
+
+    Ok(Plan { version: SCHEMA_VERSION, files })
+}
+
+/// Parse a plan saved as JSON.
+pub fn parse_plan(filename: &str, content: &str) -> KilnResult<Plan> {
+    let plan: Plan = match serde_json::from_str(content) {
+        Ok(plan) => plan,
+        Err(e) => {
+            let diagnostic = diagnostic_at(filename, content, e.line(), e.column(), e.to_string());
+            return Err(KilnError::from_diagnostics(filename, vec![diagnostic]));
+        },
+    };
+
+    if plan.version > SCHEMA_VERSION {
+        return Err(KilnError::new(
+            KilnErrorKind::Parse,
+            format!("{} uses schema version {}, but kiln only understands up to version {}", filename, plan.version, SCHEMA_VERSION)
+        ));
+    }
+
+    Ok(plan)
+}
+
+/// Convert the changes planned for a file back into a `FileDiff`.
+pub fn planned_diff(file: &PlannedFile) -> KilnResult<FileDiff> {
+    let mut filediff = FileDiff { filepath: file.path.clone(), diffs: Vec::new() };
+
+    for change in &file.changes {
+        filediff.diffs.push(match change.clone() {
+            JsonChange::Add { id, new } => Diff::Add(json_to_tag(id, new, None)?),
+            JsonChange::Delete { id, old } => Diff::Delete(json_to_tag(id, old, None)?),
+            JsonChange::Modify { id, old, new } => Diff::Modify(json_to_tag(id, old, None)?, json_to_tag(id, new, None)?),
+        });
+    }
+
+    Ok(filediff)
+}
+
+/// Check that none of the files in a plan have changed since it was made,
+/// failing with a list of every change that was found if any have. Each file
+/// is read afresh, and what was read is left in the cache to be written.
+pub fn check_plan(plan: &Plan, cache: &mut TagCache) -> KilnResult<()> {
+    let filepaths = plan.files.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+    cache.refresh_all(&filepaths)?;
+
+    let mut problems = Vec::new();
+    for file in &plan.files {
+        let path = file.path.display();
+        let sha256 = cache.hash(&file.path)?;
+        let tag_set = cache.tag_set(&file.path)?;
+        let count = problems.len();
+
+        for diff in planned_diff(file)?.diffs {
+            let (frame, expected) = match diff {
+                Diff::Add(new) => (new, None),
+                Diff::Delete(old) | Diff::Modify(old, _) => (old.clone(), Some(old.val)),
+            };
+            let id = frame.id;
+
+            match (tag_set.iter().find(|e| e.same_frame(&frame)), expected) {
+                (None, None) => {},
+                (Some(current), Some(expected)) if current.val == expected => {},
+                (Some(_), None) => problems.push(format!("{}: {:?} has been added", path, id)),
+                (None, Some(_)) => problems.push(format!("{}: {:?} has been deleted", path, id)),
+                (Some(_), Some(_)) => problems.push(format!("{}: {:?} has been changed", path, id)),
+            }
+        }
+
+        // Only bother with the hash when the tags themselves look fine, so
+        // we don't report the same file twice
+        if let Some(expected) = &file.sha256 {
+            if problems.len() == count && sha256 != *expected {
+                problems.push(format!("{}: the file has been changed", path));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(KilnError::new(
+        KilnErrorKind::File,
+        format!("the plan can't be applied, since files have changed since it was made:\n{}", problems.join("\n"))
+    ))
+}
+
+/// Check a plan, and if nothing has changed since it was made, write every
+/// change in it out with the given version of id3.
+pub fn apply_plan(plan: &Plan, id3_version: Id3Version) -> KilnResult<()> {
+    let mut cache = TagCache::new();
+    check_plan(plan, &mut cache)?;
+
+    let diff = plan.files.iter()
+        .map(planned_diff)
+        .collect::<KilnResult<Vec<_>>>()?;
+    let errors = plan.files.iter()
+        .zip(write_file_diffs(diff, id3_version, &mut cache))
+        .filter_map(|(file, res)| res.err().map(|e| (file.path.clone(), e)))
+        .collect();
+
+    path_errors(errors, "written")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::read_tag_set;
+
+    #[test]
+    fn headers_that_match_nothing_are_errors() {
+        let dir = std::env::temp_dir().join(format!("kiln-unmatched-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.mp3"), []).unwrap();
+
+        let section = |name: &str| Section {
+            header: dir.join(name).to_string_lossy().to_string(),
+            tag_set: TagSet::new(),
+        };
+        let mut cache = TagCache::new();
+
+        let matched = get_old_tags_from_sections(&vec![section("*.mp3")], &mut cache);
+        assert_eq!(matched.unwrap().len(), 1);
+
+        let unmatched = get_old_tags_from_sections(&vec![section("*.mp3"), section("b.mp3")], &mut cache);
+        let message = unmatched.map(|_| ()).unwrap_err().to_string();
+        assert!(message.contains("b.mp3"), "{}", message);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn txxx_frames_are_told_apart_by_description() {
+        let txxx = |val: &str| TagPair::from_str("TXXX", val).unwrap();
+        let old_set = TagSet::from([txxx("GAIN: -6 dB"), txxx("ID: abc")]);
+        let new_set = TagSet::from([txxx("GAIN: -7 dB"), txxx("ID: abc"), txxx("MOOD: calm")]);
+
+        let mut diffs = diff_tag_sets(String::from("a.mp3"), &old_set, &new_set, &[]).diffs;
+        diffs.sort_by_key(|e| matches!(e, Diff::Add(_)));
+        assert!(matches!(&diffs[..], [
+            Diff::Modify(old, new),
+            Diff::Add(added),
+        ] if *old == txxx("GAIN: -6 dB") && *new == txxx("GAIN: -7 dB") && *added == txxx("MOOD: calm")));
+    }
+
+    // The comment keeps its description, so the new one takes its place
+    // rather than being added alongside it
+    #[test]
+    fn described_comments_are_modified_in_place() {
+        let dir = std::env::temp_dir().join(format!("kiln-modify-comm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.mp3");
+        fs::write(&path, []).unwrap();
+
+        let comm = |val: &str| TagPair::from_str("COMM", val).unwrap();
+        let mut tag = Tag::new();
+        tag.add_frame(Frame::with_content("COMM", comm("iTunNORM: 0000").val));
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let filediff = FileDiff {
+            filepath: path.clone(),
+            diffs: vec![Diff::Modify(comm("iTunNORM: 0000"), comm("iTunNORM: 1111"))],
+        };
+        write_file_diff(filediff, Id3Version::V24, &mut TagCache::new()).unwrap();
+        assert_eq!(read_tag_set(&path).unwrap(), TagSet::from([comm("iTunNORM: 1111")]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Deleting or changing one comment leaves the file's other comments be
+    #[test]
+    fn comments_are_changed_one_at_a_time() {
+        let dir = std::env::temp_dir().join(format!("kiln-several-comm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.mp3");
+        fs::write(&path, []).unwrap();
+
+        let comm = |val: &str| TagPair::from_str("COMM", val).unwrap();
+        let mut tag = Tag::new();
+        for val in ["hi", "iTunNORM: 0000", "[deu] Hallo"] {
+            tag.add_frame(Frame::with_content("COMM", comm(val).val));
+        }
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let filediff = FileDiff {
+            filepath: path.clone(),
+            diffs: vec![Diff::Delete(comm("iTunNORM: 0000")), Diff::Modify(comm("hi"), comm("hello"))],
+        };
+        write_file_diff(filediff, Id3Version::V24, &mut TagCache::new()).unwrap();
+        assert_eq!(read_tag_set(&path).unwrap(), TagSet::from([comm("hello"), comm("[deu] Hallo")]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,466 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{
+        tag,
+        tag_no_case,
+        take_while1,
+    },
+    character::complete::{
+        char,
+        multispace0,
+        satisfy,
+    },
+    combinator::{
+        consumed,
+        map,
+        not,
+    },
+    error::VerboseError,
+    multi::many0 as many,
+    sequence::{
+        delimited,
+        preceded,
+        terminated,
+    },
+    IResult,
+};
+use regex::Regex;
+use std::{
+    cmp::Ordering,
+    path::PathBuf,
+};
+
+use crate::{
+    files::TagCache,
+    parse::{
+        failure,
+        grammar_error,
+        quoted_value,
+    },
+    types::{
+        diagnostic::Diagnostic,
+        id3::{
+            TagId,
+            TagSet,
+        },
+        kiln::{
+            FileDiff,
+            KilnError,
+            KilnResult,
+        },
+        query::{
+            CompareOp,
+            Query,
+        },
+    },
+};
+
+// What errors are reported against, since the expression comes from the command line
+const FILENAME: &str = "--where";
+
+#[derive(Debug)]
+enum RawQuery<'a> {
+    And(Vec<RawQuery<'a>>),
+    Or(Vec<RawQuery<'a>>),
+    Not(Box<RawQuery<'a>>),
+    Test {
+        name: &'a str,
+        id: &'a str,
+    },
+    Compare {
+        id: &'a str,
+        op: &'a str,
+        span: &'a str,
+        val: String,
+    },
+}
+
+/// Parse a `--where` expression, such as
+/// `TPE1 ~ "Maps" and TYER >= 2020 and missing(APIC)`.
+pub fn parse_query(expression: &str) -> KilnResult<Query> {
+    let (remaining, raw_query) = match terminated(or_query, multispace0)(expression) {
+        Ok((remaining, raw_query)) => (remaining, raw_query),
+        Err(e) => return Err(grammar_error(FILENAME, expression, e)),
+    };
+
+    let mut diagnostics = Vec::new();
+    if !remaining.is_empty() {
+        diagnostics.push(Diagnostic::from_span(
+            FILENAME,
+            expression,
+            remaining,
+            "expected 'and', 'or' or the end of the expression".to_string(),
+        ));
+    }
+
+    let query = resolve(raw_query, expression, &mut diagnostics);
+    match query {
+        Some(query) if diagnostics.is_empty() => Ok(query),
+        _ => Err(KilnError::from_diagnostics(FILENAME, diagnostics)),
+    }
+}
+
+/// Whether a file with these tags matches the query. A frame with several
+/// values matches if any one of them does.
+pub fn query_matches(query: &Query, tag_set: &TagSet) -> bool {
+    let values = |id: &TagId| tag_set.iter()
+        .filter(|e| e.id == *id)
+        .flat_map(|e| e.values())
+        .collect::<Vec<_>>();
+
+    match query {
+        Query::And(queries) => queries.iter().all(|e| query_matches(e, tag_set)),
+        Query::Or(queries) => queries.iter().any(|e| query_matches(e, tag_set)),
+        Query::Not(query) => !query_matches(query, tag_set),
+        Query::Has(id) => tag_set.iter().any(|e| e.id == *id),
+        Query::Equals(id, val) => values(id).iter().any(|e| e == val),
+        Query::Matches(id, regex) => values(id).iter().any(|e| regex.is_match(e)),
+        Query::Compare(id, op, val) => values(id).iter().any(|e| compare(e, *op, val)),
+    }
+}
+
+/// Keep only the files whose tags match the query.
+pub fn filter_paths(filepaths: Vec<PathBuf>, query: &Query, cache: &mut TagCache) -> KilnResult<Vec<PathBuf>> {
+    let mut filtered = Vec::new();
+    for filepath in filepaths {
+        if query_matches(query, &cache.tag_set(&filepath)?) {
+            filtered.push(filepath);
+        }
+    }
+
+    Ok(filtered)
+}
+
+/// Keep only the changes for files whose tags, as they are now, match the query.
+pub fn filter_diff(diff: Vec<FileDiff>, query: &Query, cache: &mut TagCache) -> KilnResult<Vec<FileDiff>> {
+    let mut filtered = Vec::new();
+    for filediff in diff {
+        if query_matches(query, &cache.tag_set(&filediff.filepath)?) {
+            filtered.push(filediff);
+        }
+    }
+
+    Ok(filtered)
+}
+
+// Numbers compare as numbers, using the number each value starts with, so
+// that "3/12" is track 3, and "2020-05-01" and "2020.05.01" are the year 2020
+fn compare(val: &str, op: CompareOp, bound: &str) -> bool {
+    let ordering = match bound.parse::<f64>() {
+        Ok(bound) => match leading_number(val) {
+            Some(val) => val.partial_cmp(&bound),
+            None => None,
+        },
+        Err(_) => Some(val.cmp(bound)),
+    };
+
+    match (op, ordering) {
+        (_, None) => false,
+        (CompareOp::Less, Some(ordering)) => ordering == Ordering::Less,
+        (CompareOp::LessEqual, Some(ordering)) => ordering != Ordering::Greater,
+        (CompareOp::Greater, Some(ordering)) => ordering == Ordering::Greater,
+        (CompareOp::GreaterEqual, Some(ordering)) => ordering != Ordering::Less,
+    }
+}
+
+fn leading_number(val: &str) -> Option<f64> {
+    let val = val.trim_start();
+    let digits = |val: &str| val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+
+    // A fraction followed by another '.' is part of a date, not the number
+    let mut end = digits(val);
+    if let Some(fraction) = val[end..].strip_prefix('.') {
+        let fraction_end = end + 1 + digits(fraction);
+        if !val[fraction_end..].starts_with('.') {
+            end = fraction_end;
+        }
+    }
+    val[..end].parse().ok()
+}
+
+fn resolve(raw: RawQuery, content: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<Query> {
+    let mut resolve_all = |raw_queries: Vec<RawQuery>| raw_queries.into_iter()
+        .map(|e| resolve(e, content, diagnostics))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect::<Option<Vec<_>>>();
+
+    match raw {
+        RawQuery::And(raw_queries) => resolve_all(raw_queries).map(Query::And),
+        RawQuery::Or(raw_queries) => resolve_all(raw_queries).map(Query::Or),
+        RawQuery::Not(raw_query) => resolve(*raw_query, content, diagnostics).map(|e| Query::Not(Box::new(e))),
+        RawQuery::Test { name, id } => {
+            let id = resolve_id(id, content, diagnostics)?;
+            match name.to_lowercase().as_str() {
+                "has" => Some(Query::Has(id)),
+                "missing" => Some(Query::Not(Box::new(Query::Has(id)))),
+                _ => {
+                    diagnostics.push(Diagnostic::from_span(
+                        FILENAME, content, name, format!("unknown test '{}', expected 'has' or 'missing'", name)
+                    ));
+                    None
+                },
+            }
+        },
+        RawQuery::Compare { id, op, span, val } => {
+            let id = resolve_id(id, content, diagnostics)?;
+            let query = match op {
+                "=" | "==" => Query::Equals(id, val),
+                "!=" => Query::Not(Box::new(Query::Equals(id, val))),
+                "~" | "!~" => {
+                    let regex = match Regex::new(&val) {
+                        Ok(regex) => regex,
+                        Err(e) => {
+                            // The regex crate draws its own caret, but the diagnostic already points at the value
+                            let message = e.to_string();
+                            let reason = message.lines().last().unwrap_or_default().trim_start_matches("error: ");
+                            diagnostics.push(Diagnostic::from_span(FILENAME, content, span, format!("bad regex: {}", reason)));
+                            return None;
+                        },
+                    };
+                    match op {
+                        "~" => Query::Matches(id, regex),
+                        _ => Query::Not(Box::new(Query::Matches(id, regex))),
+                    }
+                },
+                "<" => Query::Compare(id, CompareOp::Less, val),
+                "<=" => Query::Compare(id, CompareOp::LessEqual, val),
+                ">" => Query::Compare(id, CompareOp::Greater, val),
+                _ => Query::Compare(id, CompareOp::GreaterEqual, val),
+            };
+            Some(query)
+        },
+    }
+}
+
+fn resolve_id(id: &str, content: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<TagId> {
+    match id.parse::<TagId>() {
+        Ok(id) => Some(id),
+        Err(_) => {
+            diagnostics.push(Diagnostic::from_span(FILENAME, content, id, format!("{} is not a valid id3 tag for kiln", id)));
+            None
+        },
+    }
+}
+
+// Either side of 'and' and 'or' with only one query is just that query
+fn combine<'a>(first: RawQuery<'a>, mut rest: Vec<RawQuery<'a>>, join: fn(Vec<RawQuery<'a>>) -> RawQuery<'a>) -> RawQuery<'a> {
+    match rest.is_empty() {
+        true => first,
+        false => {
+            rest.insert(0, first);
+            join(rest)
+        },
+    }
+}
+
+fn or_query(input: &str) -> IResult<&str, RawQuery<'_>, VerboseError<&str>> {
+    let (i, first) = and_query(input)?;
+    let (i, rest) = many(preceded(delimited(multispace0, keyword("or"), multispace0), and_query))(i)?;
+    Ok((i, combine(first, rest, RawQuery::Or)))
+}
+
+fn and_query(input: &str) -> IResult<&str, RawQuery<'_>, VerboseError<&str>> {
+    let (i, first) = not_query(input)?;
+    let (i, rest) = many(preceded(delimited(multispace0, keyword("and"), multispace0), not_query))(i)?;
+    Ok((i, combine(first, rest, RawQuery::And)))
+}
+
+fn not_query(input: &str) -> IResult<&str, RawQuery<'_>, VerboseError<&str>> {
+    let (i, _) = multispace0(input)?;
+    match terminated(keyword("not"), multispace0)(i) {
+        Ok((i, _)) => map(not_query, |e| RawQuery::Not(Box::new(e)))(i),
+        Err(_) => atom(i),
+    }
+}
+
+// A parenthesised expression, a test like 'missing(APIC)', or a comparison
+// like 'TPE1 = value'
+fn atom(input: &str) -> IResult<&str, RawQuery<'_>, VerboseError<&str>> {
+    if let Ok((i, _)) = char::<_, VerboseError<&str>>('(')(input) {
+        let (i, raw_query) = or_query(i)?;
+        let (i, _) = multispace0(i)?;
+        return match char::<_, VerboseError<&str>>(')')(i) {
+            Ok((i, _)) => Ok((i, raw_query)),
+            Err(_) => Err(failure(i, "expected ')'")),
+        };
+    }
+
+    let (i, word) = match identifier(input) {
+        Ok(result) => result,
+        Err(_) => return Err(failure(input, "expected a comparison like 'TPE1 = value', or a test like 'missing(APIC)'")),
+    };
+    let (i, _) = multispace0(i)?;
+
+    if let Ok((i, _)) = char::<_, VerboseError<&str>>('(')(i) {
+        let (i, _) = multispace0(i)?;
+        let (i, id) = identifier(i).map_err(|_| failure(i, "expected a tag"))?;
+        let (i, _) = multispace0(i)?;
+        let (i, _) = char::<_, VerboseError<&str>>(')')(i).map_err(|_| failure(i, "expected ')'"))?;
+        return Ok((i, RawQuery::Test { name: word, id }));
+    }
+
+    let (i, op) = operator(i).map_err(|_| failure(i, "expected one of '=', '!=', '~', '!~', '<', '<=', '>' or '>='"))?;
+    let (i, _) = multispace0(i)?;
+    let (i, (span, val)) = match consumed(alt((quoted_value, map(bare_value, String::from))))(i) {
+        Ok(result) => result,
+        Err(nom::Err::Error(_)) => return Err(failure(i, "expected a value")),
+        Err(e) => return Err(e),
+    };
+
+    Ok((i, RawQuery::Compare { id: word, op, span, val }))
+}
+
+fn operator(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    alt((tag("=="), tag("!="), tag("!~"), tag("<="), tag(">="), tag("="), tag("~"), tag("<"), tag(">")))(input)
+}
+
+fn identifier(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
+}
+
+// Values without quotes run until whitespace or a parenthesis
+fn bare_value(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    take_while1(|c: char| !c.is_whitespace() && c != '(' && c != ')' && c != '"')(input)
+}
+
+// Keywords are whole words, in any case
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+    terminated(tag_no_case(word), not(satisfy(|c: char| c.is_alphanumeric() || c == '_')))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use id3::{
+        Tag,
+        TagLike,
+        Version,
+    };
+
+    use super::*;
+    use crate::types::{
+        id3::TagPair,
+        kiln::Diff,
+    };
+
+    fn tags(frames: &[(&str, &str)]) -> TagSet {
+        frames.iter().map(|(id, val)| TagPair::from_str(id, val).unwrap()).collect()
+    }
+
+    fn matches(expression: &str, frames: &[(&str, &str)]) -> bool {
+        query_matches(&parse_query(expression).unwrap(), &tags(frames))
+    }
+
+    fn parse_error(expression: &str) -> String {
+        parse_query(expression).unwrap_err().message
+    }
+
+    #[test]
+    fn not_binds_tightest_then_and_then_or() {
+        let frames = [("TPE1", "a"), ("TALB", "x")];
+        assert!(matches("TPE1 = a or TPE1 = b and TALB = c", &frames));
+        assert!(!matches("(TPE1 = a or TPE1 = b) and TALB = c", &frames));
+        assert!(!matches("not TPE1 = a and TALB = x", &frames));
+        assert!(matches("not (TPE1 = a and TALB = c)", &frames));
+        assert!(matches("not not TPE1 = a", &frames));
+        assert!(matches("TPE1 = b OR TALB = x AND Not TPE1 = c", &frames));
+
+        let query = parse_query("TPE1 = a or TPE1 = b and TALB = c").unwrap();
+        assert!(matches!(&query, Query::Or(queries) if matches!(&queries[1], Query::And(_))));
+    }
+
+    #[test]
+    fn quoted_values_can_hold_anything() {
+        let frames = [("TIT2", "Opal Drifters (Live) and \"more\"")];
+        assert!(matches(r#"TIT2 = "Opal Drifters (Live) and \"more\"""#, &frames));
+        assert!(matches(r#"TIT2 ~ "\\(Live\\)""#, &frames));
+        assert!(!matches("TIT2 = Opal", &frames));
+        assert!(matches("TPE1 = \"\"", &[("TPE1", "")]));
+    }
+
+    #[test]
+    fn numbers_compare_as_numbers_and_everything_else_as_text() {
+        assert!(matches("TRCK > 9", &[("TRCK", "10")]));
+        assert!(matches("TRCK < 4", &[("TRCK", "3/12")]));
+        assert!(matches("TRCK <= 3", &[("TRCK", " 3")]));
+        assert!(matches("TRCK >= 2.5", &[("TRCK", "2.5")]));
+        assert!(matches("TDRC >= 2020 and TDRC < 2021", &[("TDRC", "2020-05-01")]));
+        assert!(matches("TDRC >= 2020 and TDRC <= 2020", &[("TDRC", "2020.05.01")]));
+        assert!(matches("TDRC > 2020", &[("TDRC", "2020.5")]));
+
+        // A value that doesn't start with a number never compares to one
+        assert!(!matches("TRCK < 10", &[("TRCK", "Side A")]));
+        assert!(!matches("TRCK >= 10", &[("TRCK", "Side A")]));
+
+        // Text compares as text, so "10" comes before "9"
+        assert!(matches("TRCK < \"9x\"", &[("TRCK", "10")]));
+        assert!(matches("TPE1 < B", &[("TPE1", "Apple")]));
+        assert!(!matches("TPE1 >= b", &[("TPE1", "Apple")]));
+    }
+
+    #[test]
+    fn any_value_of_a_frame_can_match() {
+        let frames = [("TCON", "Rock\0Jazz")];
+        assert!(matches("TCON = Jazz", &frames));
+        assert!(matches("TCON != Jazz or TCON = Rock", &frames));
+        assert!(!matches("TCON != Jazz", &frames));
+    }
+
+    #[test]
+    fn missing_frames_only_match_negations() {
+        let frames = [("TPE1", "a")];
+        assert!(matches("missing(APIC)", &frames));
+        assert!(matches("HAS(TPE1) and not has(TALB)", &frames));
+        assert!(!matches("TYER < 3000", &frames));
+        assert!(!matches("TYER >= 0", &frames));
+        assert!(!matches("TALB ~ \".*\"", &frames));
+        assert!(matches("TALB != x", &frames));
+        assert!(matches("TALB !~ x", &frames));
+    }
+
+    #[test]
+    fn bad_expressions_say_what_was_expected() {
+        assert!(parse_error("").contains("expected a comparison"));
+        assert!(parse_error("TPE1").contains("expected one of '='"));
+        assert!(parse_error("TPE1 =").contains("expected a value"));
+        assert!(parse_error("TPE1 = a and").contains("expected a comparison"));
+        assert!(parse_error("TPE1 = a TALB = b").contains("expected 'and', 'or'"));
+        assert!(parse_error("(TPE1 = a").contains("expected ')'"));
+        assert!(parse_error("has(TPE1").contains("expected ')'"));
+        assert!(parse_error("TPE9 = a").contains("TPE9 is not a valid id3 tag"));
+        assert!(parse_error("present(TPE1)").contains("unknown test 'present'"));
+        assert!(parse_error("TPE1 ~ \"(\"").contains("bad regex"));
+
+        // Every mistake is reported, not just the first
+        let message = parse_error("TPE9 = a or TPE8 = b");
+        assert!(message.contains("TPE9") && message.contains("TPE8"));
+    }
+
+    #[test]
+    fn only_matching_files_are_kept() {
+        let dir = std::env::temp_dir().join(format!("kiln-query-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let filepaths = ["a.mp3", "b.mp3"].map(|e| dir.join(e));
+        for (filepath, artist) in filepaths.iter().zip(["Maps", "Opal"]) {
+            fs::write(filepath, []).unwrap();
+            let mut tag = Tag::new();
+            tag.set_artist(artist);
+            tag.write_to_path(filepath, Version::Id3v24).unwrap();
+        }
+
+        let query = parse_query("TPE1 = Maps").unwrap();
+        let mut cache = TagCache::new();
+        let filtered = filter_paths(filepaths.to_vec(), &query, &mut cache).unwrap();
+        assert_eq!(filtered, [filepaths[0].clone()]);
+
+        let diff = filepaths.iter()
+            .map(|e| FileDiff { filepath: e.clone(), diffs: vec![Diff::Add(TagPair::from_str("TALB", "New").unwrap())] })
+            .collect();
+        let filtered = filter_diff(diff, &query, &mut cache).unwrap();
+        assert_eq!(filtered.iter().map(|e| &e.filepath).collect::<Vec<_>>(), [&filepaths[0]]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
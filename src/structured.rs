@@ -0,0 +1,125 @@
+use glob::Pattern;
+use std::path::Path;
+
+use crate::{
+    json::json_to_tags,
+    types::{
+        json::SCHEMA_VERSION,
+        kiln::{
+            KilnDocument,
+            KilnError,
+            KilnErrorKind,
+            KilnResult,
+            Section,
+        },
+        structured::StructuredDocument,
+    },
+};
+
+/// Parse a YAML document in the schema `kiln list --format yaml` prints.
+pub fn parse_yaml_file(filename: &str, content: &str, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    match serde_yaml::from_str(content) {
+        Ok(document) => structured_to_document(filename, document, dir),
+        Err(e) => Err(KilnError::new(KilnErrorKind::Parse, format!("{}: {}", filename, e))),
+    }
+}
+
+/// Parse a TOML document in the schema `kiln list --format toml` prints.
+pub fn parse_toml_file(filename: &str, content: &str, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    match toml::from_str(content) {
+        Ok(document) => structured_to_document(filename, document, dir),
+        Err(e) => Err(KilnError::new(KilnErrorKind::Parse, format!("{}: {}", filename, e))),
+    }
+}
+
+fn structured_to_document(filename: &str, document: StructuredDocument, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    if document.version > SCHEMA_VERSION {
+        return Err(KilnError::new(
+            KilnErrorKind::Parse,
+            format!("{} uses schema version {}, but kiln only understands up to version {}", filename, document.version, SCHEMA_VERSION)
+        ));
+    }
+
+    let path_mode = document.paths.unwrap_or_default();
+    let dir = path_mode.base_dir(dir);
+    let mut sections = Vec::new();
+    for (index, section) in document.sections.into_iter().enumerate() {
+        // Globs are used as they are, but paths are literal and must be escaped
+        let header = match (section.glob, section.path) {
+            (Some(glob), None) => glob,
+            (None, Some(path)) => Pattern::escape(&path),
+            _ => return Err(KilnError::new(
+                KilnErrorKind::Parse,
+                format!("{}: section {} must have exactly one of 'glob' or 'path'", filename, index + 1)
+            )),
+        };
+
+        sections.push(Section { header, tag_set: json_to_tags(section.tags, dir)? });
+    }
+
+    Ok(KilnDocument {
+        path_mode,
+        sections,
+        preserved_tags: Vec::new(),
+        warnings: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        json::{
+            tags_to_json,
+            tests::tag_set,
+        },
+        types::{
+            id3::TagSet,
+            kiln::PathMode,
+            structured::StructuredSection,
+        },
+    };
+
+    fn structured_document(shared: &TagSet, file: &TagSet) -> StructuredDocument {
+        StructuredDocument {
+            version: SCHEMA_VERSION,
+            paths: Some(PathMode::Cwd),
+            sections: vec![
+                StructuredSection { glob: Some(String::from("./*")), path: None, tags: tags_to_json(shared, true) },
+                StructuredSection { glob: None, path: Some(String::from("a [1].mp3")), tags: tags_to_json(file, true) },
+            ],
+        }
+    }
+
+    fn check_document(document: KilnDocument, shared: &TagSet, file: &TagSet) -> Result<(), TestCaseError> {
+        prop_assert_eq!(document.path_mode, PathMode::Cwd);
+        prop_assert_eq!(&document.sections[0].header, "./*");
+        prop_assert_eq!(&document.sections[0].tag_set, shared);
+        prop_assert_eq!(&document.sections[1].header, &Pattern::escape("a [1].mp3"));
+        prop_assert_eq!(&document.sections[1].tag_set, file);
+        Ok(())
+    }
+
+    #[test]
+    fn sections_need_a_glob_or_a_path() {
+        let content = "version: 2\nsections:\n- tags: {}\n";
+        let message = parse_yaml_file("test.yaml", content, None).map(|_| ()).unwrap_err().to_string();
+        assert!(message.contains("exactly one of 'glob' or 'path'"), "{}", message);
+    }
+
+    proptest! {
+        #[test]
+        fn any_tags_survive_yaml(shared in tag_set(true), file in tag_set(true)) {
+            let content = serde_yaml::to_string(&structured_document(&shared, &file)).unwrap();
+            check_document(parse_yaml_file("test.yaml", &content, None).unwrap(), &shared, &file)?;
+        }
+
+        #[test]
+        fn any_tags_survive_toml(shared in tag_set(true), file in tag_set(true)) {
+            let content = toml::to_string(&structured_document(&shared, &file)).unwrap();
+            check_document(parse_toml_file("test.toml", &content, None).unwrap(), &shared, &file)?;
+        }
+    }
+}
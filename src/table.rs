@@ -0,0 +1,228 @@
+use csv::{
+    ReaderBuilder,
+    WriterBuilder,
+};
+use glob::Pattern;
+use id3::frame::Content;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+};
+
+use crate::types::{
+    id3::{
+        TagId,
+        TagPair,
+        TagSet,
+    },
+    kiln::{
+        KilnDocument,
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+        PathMode,
+        Section,
+    },
+};
+
+// Spreadsheets have no sensible way to hold a picture, so APIC is left out
+// entirely, and is never touched when a table is read back in
+fn in_table(tag: &TagPair) -> bool {
+    !matches!(tag.val, Content::Picture(_))
+}
+
+/// Write rows of tags as a CSV or TSV table, depending on the delimiter.
+// Tables have one row per file and one column per frame value, with the
+// column repeated as many times as the most values any one file has
+pub fn write_table(rows: &[(String, TagSet)], delimiter: u8, out: &mut dyn Write) -> KilnResult<()> {
+    let mut columns: BTreeMap<TagId, usize> = BTreeMap::new();
+    for (_, tag_set) in rows {
+        for tag in tag_set.iter().filter(|e| in_table(e)) {
+            let count = columns.entry(tag.id).or_default();
+            *count = (*count).max(frame_values(tag_set, tag.id).len());
+        }
+    }
+
+    let mut writer = WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(out);
+
+    let mut header = vec![String::from("path")];
+    for (id, count) in &columns {
+        header.extend(std::iter::repeat_n(format!("{:?}", id), *count));
+    }
+    write_record(&mut writer, &header)?;
+
+    for (path, tag_set) in rows {
+        let mut record = vec![path.clone()];
+        for (id, count) in &columns {
+            let mut values = frame_values(tag_set, *id);
+            values.resize(*count, String::new());
+            record.extend(values);
+        }
+        write_record(&mut writer, &record)?;
+    }
+
+    match writer.flush() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Every value of a frame, where a file with several TXXX frames has them all
+// in their own columns, sorted so they line up from file to file
+fn frame_values(tag_set: &TagSet, id: TagId) -> Vec<String> {
+    let mut tags = tag_set.iter()
+        .filter(|e| e.id == id)
+        .collect::<Vec<_>>();
+    tags.sort_by_key(|e| e.values());
+
+    tags.iter().flat_map(|e| e.values()).collect()
+}
+
+fn write_record<W: Write>(writer: &mut csv::Writer<W>, record: &[String]) -> KilnResult<()> {
+    match writer.write_record(record) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(KilnError::new(KilnErrorKind::File, e.to_string())),
+    }
+}
+
+/// Parse a CSV or TSV table, depending on the delimiter. Empty cells only
+/// delete their tags with `delete_empty`.
+pub fn parse_table_file(filename: &str, content: &str, delimiter: u8, delete_empty: bool, dir: Option<&Path>) -> KilnResult<KilnDocument> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(content.as_bytes());
+
+    let header = match reader.headers() {
+        Ok(header) => header.clone(),
+        Err(e) => return Err(table_error(filename, e)),
+    };
+
+    match header.get(0) {
+        Some(column) if column.eq_ignore_ascii_case("path") => {},
+        _ => return Err(KilnError::new(KilnErrorKind::Parse, format!("{}:1: the first column must be 'path'", filename))),
+    }
+
+    let mut columns = Vec::new();
+    for column in header.iter().skip(1) {
+        match column.parse::<TagId>() {
+            Ok(id) => columns.push(id),
+            Err(_) => return Err(KilnError::new(
+                KilnErrorKind::ID3,
+                format!("{}:1: {} is not a valid id3 tag for kiln", filename, column)
+            )),
+        }
+    }
+
+    let mut sections = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => return Err(table_error(filename, e)),
+        };
+        let line = record.position().map_or(0, |e| e.line());
+
+        let mut tag_pairs: Vec<TagPair> = Vec::new();
+        for (id, cell) in columns.iter().zip(record.iter().skip(1)) {
+            // Empty cells are simply missing values, and whether that means
+            // the frame gets deleted is down to which tags we preserve
+            if cell.is_empty() { continue; }
+
+            let tag_pair = match TagPair::from_str_in(&format!("{:?}", id), cell, dir) {
+                Ok(tag_pair) => tag_pair,
+                Err(e) => return Err(KilnError::new(e.kind, format!("{}:{}: {}", filename, line, e.message))),
+            };
+            match tag_pairs.iter_mut().find(|e| e.same_frame(&tag_pair)) {
+                Some(existing) => if let Err(e) = existing.append(tag_pair) {
+                    return Err(KilnError::new(e.kind, format!("{}:{}: {}", filename, line, e.message)));
+                },
+                None => tag_pairs.push(tag_pair),
+            }
+        }
+
+        // Paths in a table are literal, so we escape them before they're globbed
+        let path = record.get(0).unwrap_or_default();
+        sections.push(Section {
+            header: Pattern::escape(path),
+            tag_set: tag_pairs.into_iter().collect(),
+        });
+    }
+
+    // Unless asked to treat empty cells as deletions, nothing is ever deleted,
+    // and even then we leave alone any frames that have no column at all
+    let preserved_tags = TagId::ALL.iter()
+        .filter(|id| !delete_empty || !columns.contains(id))
+        .copied()
+        .collect();
+
+    Ok(KilnDocument { path_mode: PathMode::default(), sections, preserved_tags, warnings: Vec::new() })
+}
+
+fn table_error(filename: &str, e: csv::Error) -> KilnError {
+    let line = e.position().map_or(0, |e| e.line());
+    KilnError::new(KilnErrorKind::Parse, format!("{}:{}: {}", filename, line, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::json::tests::tag_set;
+
+    fn parse_rows(rows: &[(String, TagSet)], delimiter: u8) -> KilnDocument {
+        let mut content = Vec::new();
+        write_table(rows, delimiter, &mut content).unwrap();
+        parse_table_file("test.csv", &String::from_utf8(content).unwrap(), delimiter, false, None).unwrap()
+    }
+
+    // Empty cells are missing values, so a table can't hold an empty value
+    fn table_tag_set() -> impl Strategy<Value = TagSet> {
+        tag_set(false).prop_filter("tables can't hold empty values", |tag_set| {
+            tag_set.iter().all(|tag| tag.values().iter().all(|val| !val.is_empty()))
+        })
+    }
+
+    // A comment with a description is written as 'description: text', and
+    // has to come back as the same frame rather than one without
+    #[test]
+    fn described_comments_survive_a_table() {
+        let tag_set = TagSet::from([
+            TagPair::from_str("COMM", "hi").unwrap(),
+            TagPair::from_str("COMM", "iTunNORM: 0000").unwrap(),
+            TagPair::from_str("TXXX", "\"\": Note: this").unwrap(),
+        ]);
+        let document = parse_rows(&[(String::from("a.mp3"), tag_set.clone())], b',');
+        assert_eq!(document.sections[0].tag_set, tag_set);
+    }
+
+    #[test]
+    fn pictures_are_left_out_and_preserved() {
+        let mut tag_set = TagSet::from([TagPair::from_str("TIT2", "x").unwrap()]);
+        let picture = id3::frame::Picture {
+            mime_type: String::from("image/jpeg"),
+            picture_type: id3::frame::PictureType::CoverFront,
+            description: String::new(),
+            data: vec![1, 2, 3],
+        };
+        tag_set.insert(TagPair::from_id(TagId::APIC, Content::Picture(picture)));
+
+        let document = parse_rows(&[(String::from("a.mp3"), tag_set)], b',');
+        assert_eq!(document.sections[0].tag_set, TagSet::from([TagPair::from_str("TIT2", "x").unwrap()]));
+        assert!(document.preserved_tags.contains(&TagId::APIC));
+    }
+
+    proptest! {
+        #[test]
+        fn any_tags_survive_csv_and_tsv(a in table_tag_set(), b in table_tag_set(), tsv in any::<bool>()) {
+            let rows = vec![(String::from("a [1].mp3"), a.clone()), (String::from("b.mp3"), b.clone())];
+            let document = parse_rows(&rows, if tsv { b'\t' } else { b',' });
+
+            prop_assert_eq!(&document.sections[0].header, &Pattern::escape("a [1].mp3"));
+            prop_assert_eq!(&document.sections[0].tag_set, &a);
+            prop_assert_eq!(&document.sections[1].tag_set, &b);
+        }
+    }
+}
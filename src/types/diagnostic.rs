@@ -0,0 +1,76 @@
+use colored::Colorize;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub filename: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub source_line: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    // The span must be a slice of the source, since we locate it by pointer
+    pub fn from_span(filename: &str, source: &str, span: &str, message: String) -> Self {
+        let offset = (span.as_ptr() as usize)
+            .saturating_sub(source.as_ptr() as usize)
+            .min(source.len());
+
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+        let source_line = source[line_start..line_end].trim_end_matches('\r');
+
+        let line = source[..offset].matches('\n').count() + 1;
+        let column = source[line_start..offset].chars().count() + 1;
+
+        // Underline at most to the end of the line, and always at least one char
+        let length = span.lines().next()
+            .unwrap_or("")
+            .trim_end_matches('\r')
+            .chars()
+            .count()
+            .max(1);
+
+        Self {
+            severity: Severity::Error,
+            filename: filename.to_string(),
+            line,
+            column,
+            length,
+            source_line: source_line.to_string(),
+            message,
+        }
+    }
+
+    pub fn warning(self) -> Self {
+        Self { severity: Severity::Warning, ..self }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let padding = " ".repeat(self.column - 1);
+        let carets = "^".repeat(self.length);
+        let (label, carets) = match self.severity {
+            Severity::Error => ("error".red().bold(), carets.red().bold()),
+            Severity::Warning => ("warning".yellow().bold(), carets.yellow().bold()),
+        };
+
+        writeln!(f, "{}: {}", label, self.message.bold())?;
+        writeln!(f, "{}{} {}:{}:{}", gutter, "-->".blue().bold(), self.filename, self.line, self.column)?;
+        writeln!(f, "{} {}", gutter, "|".blue().bold())?;
+        writeln!(f, "{} {} {}", line_number.blue().bold(), "|".blue().bold(), self.source_line)?;
+        write!(f, "{} {} {}{}", gutter, "|".blue().bold(), padding, carets)
+    }
+}
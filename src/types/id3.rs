@@ -1,24 +1,42 @@
-use clap::ValueEnum;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use id3::frame::{
     Comment,
     Content,
+    ExtendedText,
     Picture,
     PictureType,
 };
 use std::{
     collections::HashSet,
     io::Cursor,
+    path::Path,
+    str::FromStr,
 };
 
-use crate::types::kiln::{
-    KilnError,
-    KilnErrorKind,
-    KilnResult,
+use crate::{
+    parse::{
+        escape_value,
+        quoted_value,
+    },
+    types::kiln::{
+        KilnError,
+        KilnErrorKind,
+        KilnResult,
+    },
 };
 
+// The language id3 gives comments when nothing else is said
+const DEFAULT_LANG: &str = "eng";
+
+/// A set of tags, like those of a file.
 pub type TagSet = HashSet<TagPair>;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, ValueEnum)]
+/// The id3 frames kiln supports.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
 pub enum TagId {
     TPE1,
     TPE2,
@@ -29,18 +47,84 @@ pub enum TagId {
     TDRC,
     TCON,
     TSRC,
+    TXXX,
     COMM,
     APIC,
 }
 
+impl TagId {
+    /// Every frame kiln supports, in the order they're declared.
+    pub const ALL: [TagId; 12] = [
+        TagId::TPE1,
+        TagId::TPE2,
+        TagId::TALB,
+        TagId::TIT2,
+        TagId::TRCK,
+        TagId::TYER,
+        TagId::TDRC,
+        TagId::TCON,
+        TagId::TSRC,
+        TagId::TXXX,
+        TagId::COMM,
+        TagId::APIC,
+    ];
+}
+
+/// Frame ids are read ignoring case, so 'tit2' is as good as 'TIT2'.
+impl FromStr for TagId {
+    type Err = KilnError;
+
+    fn from_str(id: &str) -> KilnResult<Self> {
+        match Self::ALL.iter().find(|e| format!("{:?}", e).eq_ignore_ascii_case(id)) {
+            Some(id) => Ok(*id),
+            None => Err(KilnError::new(KilnErrorKind::ID3, format!("{} is not a valid id3 tag for kiln", id))),
+        }
+    }
+}
+
+/// A frame and its content.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct TagPair {
     pub id: TagId,
     pub val: Content,
 }
 
+/// The inverse of id3's own conversion from `PictureType` to `u8`.
+pub fn picture_type_from_u8(picture_type: u8) -> PictureType {
+    match picture_type {
+        0 => PictureType::Other,
+        1 => PictureType::Icon,
+        2 => PictureType::OtherIcon,
+        3 => PictureType::CoverFront,
+        4 => PictureType::CoverBack,
+        5 => PictureType::Leaflet,
+        6 => PictureType::Media,
+        7 => PictureType::LeadArtist,
+        8 => PictureType::Artist,
+        9 => PictureType::Conductor,
+        10 => PictureType::Band,
+        11 => PictureType::Composer,
+        12 => PictureType::Lyricist,
+        13 => PictureType::RecordingLocation,
+        14 => PictureType::DuringRecording,
+        15 => PictureType::DuringPerformance,
+        16 => PictureType::ScreenCapture,
+        17 => PictureType::BrightFish,
+        18 => PictureType::Illustration,
+        19 => PictureType::BandLogo,
+        20 => PictureType::PublisherLogo,
+        n => PictureType::Undefined(n),
+    }
+}
+
 impl TagPair {
     pub fn from_str(id: &str, val: &str) -> KilnResult<Self> {
+        Self::from_str_in(id, val, None)
+    }
+
+    /// Like `from_str`, but the image path an APIC value names is read from
+    /// `dir` if it's relative, rather than from the current directory.
+    pub fn from_str_in(id: &str, val: &str, dir: Option<&Path>) -> KilnResult<Self> {
         let tag_pair = match id {
             "TPE1" => Self { id: TagId::TPE1, val: Content::Text(val.to_string()) },
             "TPE2" => Self { id: TagId::TPE2, val: Content::Text(val.to_string()) },
@@ -51,16 +135,26 @@ impl TagPair {
             "TDRC" => Self { id: TagId::TDRC, val: Content::Text(val.to_string()) },
             "TCON" => Self { id: TagId::TCON, val: Content::Text(val.to_string()) },
             "TSRC" => Self { id: TagId::TSRC, val: Content::Text(val.to_string()) },
+            "TXXX" => {
+                let (description, value) = split_description(val);
+                let text = ExtendedText { description, value };
+                Self { id: TagId::TXXX, val: Content::ExtendedText(text) }
+            },
             "COMM" => {
-                let comment = Comment {
-                    lang: String::from("eng"),
-                    description: String::new(),
-                    text: val.to_string(),
-                };
+                let (lang, val) = split_lang(val);
+                let (description, text) = split_description(val);
+                let comment = Comment { lang, description, text };
                 Self { id: TagId::COMM, val: Content::Comment(comment) }
             },
             "APIC" => {
-                let image = match image::open(val) {
+                // Pictures are front covers unless they say otherwise, like
+                // 'Back cover: back.jpg'
+                let (picture_type, val) = split_picture_type(val);
+                let path = match dir {
+                    Some(dir) => dir.join(val),
+                    None => Path::new(val).to_path_buf(),
+                };
+                let image = match image::open(path) {
                     Ok(img) => img,
                     Err(e) => return Err(KilnError::new(KilnErrorKind::Image, e.to_string()))
                 };
@@ -70,7 +164,7 @@ impl TagPair {
 
                 let picture = Picture {
                     mime_type: "image/jpeg".to_string(),
-                    picture_type: PictureType::CoverFront,
+                    picture_type,
                     description: "cover".to_string(),
                     data: encoded_image_bytes.into_inner(),
                 };
@@ -93,6 +187,7 @@ impl TagPair {
             "TDRC" => Self { id: TagId::TDRC, val },
             "TCON" => Self { id: TagId::TCON, val },
             "TSRC" => Self { id: TagId::TSRC, val },
+            "TXXX" => Self { id: TagId::TXXX, val },
             "COMM" => Self { id: TagId::COMM, val },
             "APIC" => Self { id: TagId::APIC, val },
             _ => return Err(KilnError::new(KilnErrorKind::ID3, format!("{} is not a valid id3 tag for kiln", id))),
@@ -104,4 +199,166 @@ impl TagPair {
     pub fn from_id(id: TagId, val: Content) -> Self {
         Self { id, val }
     }
+
+    // A file can only have one of most frames, but it can have a TXXX frame
+    // for each description, a COMM frame for each language and description,
+    // and an APIC frame for each picture type, just as id3 tells them apart
+    pub fn same_frame(&self, other: &TagPair) -> bool {
+        match (&self.val, &other.val) {
+            (Content::ExtendedText(a), Content::ExtendedText(b)) => a.description == b.description,
+            (Content::Comment(a), Content::Comment(b)) => a.lang == b.lang && a.description == b.description,
+            (Content::Picture(a), Content::Picture(b)) => a.picture_type == b.picture_type,
+            _ => self.id == other.id,
+        }
+    }
+
+    // Text frames in ID3v2.4 can hold several null-separated values. Every
+    // value is written just as a kiln file has it, so that it reads back in
+    // as the same frame
+    pub fn values(&self) -> Vec<String> {
+        match &self.val {
+            Content::Text(text) => text.split('\0').map(String::from).collect(),
+            Content::ExtendedText(text) => vec![join_description(&text.description, &text.value)],
+            Content::Comment(comment) => vec![format!(
+                "{}{}",
+                join_lang(&comment.lang),
+                join_description(&comment.description, &comment.text),
+            )],
+            val => vec![val.to_string()],
+        }
+    }
+
+    pub fn append(&mut self, other: TagPair) -> KilnResult<()> {
+        match (&mut self.val, other.val) {
+            (Content::Text(text), Content::Text(other)) => {
+                text.push('\0');
+                text.push_str(&other);
+                Ok(())
+            },
+            (Content::ExtendedText(_), _) => Err(KilnError::new(
+                KilnErrorKind::ID3,
+                "there can only be one TXXX frame for each description".to_string()
+            )),
+            (Content::Comment(_), _) => Err(KilnError::new(
+                KilnErrorKind::ID3,
+                "there can only be one COMM frame for each language and description".to_string()
+            )),
+            (Content::Picture(_), _) => Err(KilnError::new(
+                KilnErrorKind::ID3,
+                "there can only be one APIC frame for each picture type".to_string()
+            )),
+            _ => Err(KilnError::new(KilnErrorKind::ID3, format!("{:?} cannot hold multiple values", self.id))),
+        }
+    }
+
+    pub fn display_value(&self) -> String {
+        self.values().join("; ")
+    }
+}
+
+// Comments are in English unless they start with another language, like
+// '[deu] Kommentar'
+fn join_lang(lang: &str) -> String {
+    match lang {
+        DEFAULT_LANG => String::new(),
+        lang => format!("[{}] ", lang),
+    }
+}
+
+fn split_lang(val: &str) -> (String, &str) {
+    let lang = val.strip_prefix('[')
+        .and_then(|e| e.split_once("] "))
+        .filter(|(lang, _)| lang.chars().count() == 3);
+
+    match lang {
+        Some((lang, rest)) => (lang.to_string(), rest),
+        None => (String::from(DEFAULT_LANG), val),
+    }
+}
+
+// Written as 'description: value', just like the id3 crate displays it, but
+// with the description quoted whenever it couldn't be told apart otherwise,
+// like when it has ': ' in it, or when it's empty and the value has
+fn join_description(description: &str, value: &str) -> String {
+    let ambiguous = |e: &str| e.contains(": ") || e.starts_with(['"', '[']);
+
+    match (description.is_empty(), ambiguous(description) || ambiguous(value)) {
+        (true, false) => value.to_string(),
+        (true, true) => format!("\"\": {}", value),
+        (false, _) if ambiguous(description) => format!("\"{}\": {}", escape_value(description), value),
+        (false, _) => format!("{}: {}", description, value),
+    }
+}
+
+fn split_description(val: &str) -> (String, String) {
+    if let Ok((rest, description)) = quoted_value(val) {
+        if let Some(value) = rest.strip_prefix(": ") {
+            return (description, value.to_string());
+        }
+    }
+
+    match val.split_once(": ") {
+        Some((description, value)) => (description.to_string(), value.to_string()),
+        None => (String::new(), val.to_string()),
+    }
+}
+
+fn split_picture_type(val: &str) -> (PictureType, &str) {
+    let picture_type = (0..=20)
+        .map(picture_type_from_u8)
+        .find_map(|e| Some((e, val.strip_prefix(&e.to_string())?.strip_prefix(": ")?)));
+
+    picture_type.unwrap_or((PictureType::CoverFront, val))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        // Descriptions and values can hold anything but a null, ': ' included
+        #[test]
+        fn any_extended_text_survives_its_value(description in "[^\u{0}]*", value in "[^\u{0}]*") {
+            let tag = TagPair::from_id(TagId::TXXX, Content::ExtendedText(ExtendedText { description, value }));
+            prop_assert_eq!(TagPair::from_str("TXXX", &tag.values()[0]).unwrap(), tag);
+        }
+
+        #[test]
+        fn any_comment_survives_its_value(lang in "[a-z]{3}", description in "[^\u{0}]*", text in "[^\u{0}]*") {
+            let tag = TagPair::from_id(TagId::COMM, Content::Comment(Comment { lang, description, text }));
+            prop_assert_eq!(TagPair::from_str("COMM", &tag.values()[0]).unwrap(), tag);
+        }
+    }
+
+    #[test]
+    fn descriptions_are_only_quoted_when_they_have_to_be() {
+        let txxx = |description: &str, value: &str| {
+            let text = ExtendedText { description: description.to_string(), value: value.to_string() };
+            TagPair::from_id(TagId::TXXX, Content::ExtendedText(text)).values().remove(0)
+        };
+        assert_eq!(txxx("GAIN", "-6 dB"), "GAIN: -6 dB");
+        assert_eq!(txxx("", "plain"), "plain");
+        assert_eq!(txxx("", "Note: this"), "\"\": Note: this");
+        assert_eq!(txxx("A: B", "c"), "\"A: B\": c");
+
+        let comment = Comment { lang: String::from("deu"), description: String::new(), text: String::from("Hallo") };
+        assert_eq!(TagPair::from_id(TagId::COMM, Content::Comment(comment)).values(), vec!["[deu] Hallo"]);
+    }
+
+    #[test]
+    fn pictures_are_front_covers_unless_they_say_otherwise() {
+        assert_eq!(split_picture_type("Back cover: back.jpg"), (PictureType::CoverBack, "back.jpg"));
+        assert_eq!(split_picture_type("covers/Back cover.jpg"), (PictureType::CoverFront, "covers/Back cover.jpg"));
+    }
+    #[test]
+    fn frame_ids_are_read_ignoring_case() {
+        for id in TagId::ALL {
+            assert_eq!(format!("{:?}", id).parse::<TagId>().unwrap(), id);
+            assert_eq!(format!("{:?}", id).to_lowercase().parse::<TagId>().unwrap(), id);
+        }
+        assert!("TIT".parse::<TagId>().is_err());
+        assert!("TSSE".parse::<TagId>().is_err());
+    }
 }
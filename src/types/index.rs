@@ -0,0 +1,47 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+};
+
+use crate::types::{
+    id3::TagId,
+    json::JsonValue,
+};
+
+// Bump this whenever the index changes, so old ones are read again from scratch
+pub const INDEX_VERSION: u32 = 1;
+
+/// The tags of the files in one directory as they were when they were last
+/// read, so that files that haven't changed since don't need reading again.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DirIndex {
+    pub version: u32,
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub files: BTreeMap<String, IndexedFile>,
+}
+
+/// A file's frames, in the order the file has them, along with what the file
+/// looked like when they were read.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexedFile {
+    #[serde(flatten)]
+    pub stamp: FileStamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub frames: Vec<(TagId, JsonValue)>,
+}
+
+/// Enough about a file to tell whether it has changed: if any of these are
+/// different, it has to be read again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime: u64,
+    pub mtime_nanos: u32,
+    pub inode: u64,
+}
@@ -0,0 +1,115 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+};
+
+use crate::types::{
+    id3::TagId,
+    kiln::PathMode,
+};
+
+// Bump this whenever the schema changes in a way older versions can't read
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonDocument {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<PathMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob: Option<String>,
+    #[serde(default)]
+    pub shared: JsonTags,
+    #[serde(default)]
+    pub files: Vec<JsonFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonFile {
+    pub path: String,
+    #[serde(default)]
+    pub tags: JsonTags,
+}
+
+pub type JsonTags = BTreeMap<TagId, JsonValue>;
+
+// A file can have several COMM, TXXX and APIC frames, so when listing tags
+// they always come as lists, but single frames are still read for COMM and
+// APIC, as they were written before
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum JsonValue {
+    Text(String),
+    List(Vec<String>),
+    Picture(JsonPicture),
+    Comment(JsonComment),
+    Extended(Vec<JsonExtendedText>),
+    Pictures(Vec<JsonPicture>),
+    Comments(Vec<JsonComment>),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct JsonPicture {
+    pub mime_type: String,
+    pub picture_type: u8,
+    pub description: String,
+    #[serde(default)]
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct JsonComment {
+    #[serde(default = "default_lang")]
+    pub lang: String,
+    #[serde(default)]
+    pub description: String,
+    pub text: String,
+}
+
+// A file can have a TXXX frame for each description, so they come as a list
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct JsonExtendedText {
+    #[serde(default)]
+    pub description: String,
+    pub value: String,
+}
+
+fn default_lang() -> String {
+    String::from("eng")
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonFileDiff {
+    pub path: String,
+    // The file being compared against, when it isn't the same file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    pub changes: Vec<JsonChange>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JsonChange {
+    Add { id: TagId, new: JsonValue },
+    Delete { id: TagId, old: JsonValue },
+    Modify { id: TagId, old: JsonValue, new: JsonValue },
+}
+
+// Written by rename, so that a batch of renames can be undone later on
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonUndoLog {
+    pub version: u32,
+    pub renames: Vec<JsonRename>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JsonRename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
@@ -1,14 +1,28 @@
 use colored::Colorize;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use std::{
     fmt,
-    path::PathBuf,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
 
-use crate::types::id3::{
-    TagPair,
-    TagSet,
+use crate::types::{
+    diagnostic::Diagnostic,
+    id3::{
+        TagId,
+        TagPair,
+        TagSet,
+    },
 };
 
+/// Every error kiln can run into, with a message fit for printing.
+#[derive(Debug)]
 pub struct KilnError {
     pub kind: KilnErrorKind,
     pub message: String,
@@ -18,6 +32,20 @@ impl KilnError {
     pub fn new(kind: KilnErrorKind, message: String) -> Self {
         Self { kind, message }
     }
+
+    pub fn from_diagnostics(filename: &str, diagnostics: Vec<Diagnostic>) -> Self {
+        let count = diagnostics.len();
+        let rendered = diagnostics.iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let plural = if count == 1 { "" } else { "s" };
+
+        Self::new(
+            KilnErrorKind::Parse,
+            format!("could not parse {} due to {} error{}\n\n{}", filename, count, plural, rendered)
+        )
+    }
 }
 
 impl fmt::Display for KilnError {
@@ -26,7 +54,15 @@ impl fmt::Display for KilnError {
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for KilnError {}
+
+impl From<io::Error> for KilnError {
+    fn from(e: io::Error) -> Self {
+        Self::new(KilnErrorKind::File, e.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum KilnErrorKind {
     File,
     Glob,
@@ -37,12 +73,45 @@ pub enum KilnErrorKind {
 
 pub type KilnResult<T> = Result<T, KilnError>;
 
+// Decides what relative headers in a kiln file are resolved against
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    #[default]
+    File,
+    Cwd,
+}
+
+impl PathMode {
+    /// The directory relative paths in an input file are taken from, given
+    /// the directory the file is in, where `None` is the current directory.
+    pub fn base_dir(self, input_dir: Option<&Path>) -> Option<&Path> {
+        match self {
+            PathMode::File => input_dir,
+            PathMode::Cwd => None,
+        }
+    }
+}
+
+/// A parsed input file, in any of the formats kiln understands.
+#[derive(Debug)]
+pub struct KilnDocument {
+    pub path_mode: PathMode,
+    pub sections: Vec<Section>,
+    pub preserved_tags: Vec<TagId>,
+    /// Problems that didn't stop the file being read, for the caller to show
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// A header, which is a glob of the files it applies to, and the tags to give
+/// them.
 #[derive(Debug)]
 pub struct Section {
     pub header: String,
     pub tag_set: TagSet,
 }
 
+/// Every change to make to one file.
 pub struct FileDiff {
     pub filepath: PathBuf,
     pub diffs: Vec<Diff>,
@@ -70,6 +139,7 @@ impl fmt::Display for FileDiff {
     }
 }
 
+/// A single change to one tag of a file.
 pub enum Diff {
     Add(TagPair),
     Delete(TagPair),
@@ -79,9 +149,9 @@ pub enum Diff {
 impl fmt::Display for Diff {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Diff::Add(tag) => write!(f, "{}", format!("{} {:?}: {}", "A".bold(), tag.id, tag.val).green()),
-            Diff::Delete(tag) => write!(f, "{}", format!("{} {:?}: {}", "D".bold(), tag.id, tag.val).red()),
-            Diff::Modify(old, new) => write!(f, "{}", format!("{} {:?}: {} -> {:?}: {}", "M".bold(), old.id, old.val, new.id, new.val).yellow()),
+            Diff::Add(tag) => write!(f, "{}", format!("{} {:?}: {}", "A".bold(), tag.id, tag.display_value()).green()),
+            Diff::Delete(tag) => write!(f, "{}", format!("{} {:?}: {}", "D".bold(), tag.id, tag.display_value()).red()),
+            Diff::Modify(old, new) => write!(f, "{}", format!("{} {:?}: {} -> {:?}: {}", "M".bold(), old.id, old.display_value(), new.id, new.display_value()).yellow()),
         }
     }
 }
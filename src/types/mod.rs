@@ -1,3 +1,10 @@
-pub mod args;
+pub mod diagnostic;
 pub mod id3;
+pub mod index;
+pub mod json;
 pub mod kiln;
+pub mod normalize;
+pub mod options;
+pub mod plan;
+pub mod query;
+pub mod structured;
@@ -0,0 +1,83 @@
+use serde::Deserialize;
+
+use crate::types::id3::TagId;
+
+/// The rules `kiln normalize` applies, as read from a rules file. Only the
+/// rules the file mentions are applied.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NormalizeRules {
+    /// Put every text value into Unicode's composed form (NFC)
+    #[serde(default)]
+    pub nfc: bool,
+    /// Strip whitespace from either end of every text value
+    #[serde(default)]
+    pub trim: bool,
+    pub feat: Option<FeatRule>,
+    pub title_case: Option<TitleCaseRule>,
+    pub track: Option<TrackRule>,
+    pub date: Option<DateRule>,
+}
+
+/// Write every featuring credit, like 'ft.' or 'Featuring', the same way.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeatRule {
+    #[serde(default = "default_feat_style")]
+    pub style: String,
+    #[serde(default = "default_feat_fields")]
+    pub fields: Vec<TagId>,
+}
+
+/// Capitalise every word, except for short words like 'of' and 'the' that
+/// aren't first or last.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TitleCaseRule {
+    #[serde(default = "default_title_case_fields")]
+    pub fields: Vec<TagId>,
+    #[serde(default = "default_title_case_exceptions")]
+    pub exceptions: Vec<String>,
+}
+
+/// Write track numbers the same way, padded with zeros and with the number
+/// of tracks on the album if asked and it can be worked out.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackRule {
+    #[serde(default)]
+    pub width: usize,
+    #[serde(default)]
+    pub total: bool,
+}
+
+/// Write dates as ISO 8601, like '2024-03-09'.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DateRule {
+    #[serde(default = "default_date_fields")]
+    pub fields: Vec<TagId>,
+}
+
+fn default_feat_style() -> String {
+    String::from("feat.")
+}
+
+fn default_feat_fields() -> Vec<TagId> {
+    vec![TagId::TPE1, TagId::TIT2]
+}
+
+fn default_title_case_fields() -> Vec<TagId> {
+    vec![TagId::TALB, TagId::TIT2]
+}
+
+fn default_title_case_exceptions() -> Vec<String> {
+    [
+        "a", "an", "and", "as", "at", "but", "by", "feat.", "featuring", "for", "ft.", "in", "nor", "of", "on",
+        "or", "the", "to", "vs.",
+    ].into_iter().map(String::from).collect()
+}
+
+fn default_date_fields() -> Vec<TagId> {
+    vec![TagId::TDRC]
+}
@@ -0,0 +1,29 @@
+/// The formats kiln reads and writes tags in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Kiln,
+    Json,
+    Csv,
+    Tsv,
+    Yaml,
+    Toml,
+}
+
+/// The order frames are listed in under each header.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FrameOrder {
+    /// Alphabetically by frame id
+    Id,
+    /// Artist, album, track and title first, then the rest by id
+    #[default]
+    Preferred,
+    /// The order the frames are in the file itself
+    File,
+}
+
+/// The version of id3 tags are written with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Id3Version {
+    V23,
+    V24,
+}
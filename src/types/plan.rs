@@ -0,0 +1,27 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::path::PathBuf;
+
+use crate::types::json::JsonChange;
+
+/// Every change to make to a set of files, worked out ahead of time so that it
+/// can be saved, looked over, and applied later on.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Plan {
+    pub version: u32,
+    #[serde(default)]
+    pub files: Vec<PlannedFile>,
+}
+
+/// The changes to make to one file. The old values of each change, and the
+/// hash of the whole file if there is one, have to match what's on disk for
+/// the plan to be applied.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub changes: Vec<JsonChange>,
+}
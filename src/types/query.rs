@@ -0,0 +1,28 @@
+use regex::Regex;
+
+use crate::types::id3::TagId;
+
+/// A `--where` expression, which each file's tags either match or don't.
+#[derive(Debug)]
+pub enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    /// The file has the frame at all
+    Has(TagId),
+    /// Some value of the frame is exactly this
+    Equals(TagId, String),
+    /// Some value of the frame matches this regex
+    Matches(TagId, Regex),
+    /// Some value of the frame compares this way to this, as numbers if
+    /// this is a number, and as text otherwise
+    Compare(TagId, CompareOp, String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompareOp {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
@@ -0,0 +1,30 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::types::{
+    json::JsonTags,
+    kiln::PathMode,
+};
+
+// YAML and TOML documents mirror the sections of a kiln file directly, and
+// share their schema version and tag values with the JSON format
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StructuredDocument {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<PathMode>,
+    #[serde(default)]
+    pub sections: Vec<StructuredSection>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StructuredSection {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub tags: JsonTags,
+}